@@ -0,0 +1,583 @@
+//! A language server for URSL. It speaks LSP over stdin and stdout, so point your editor at this binary.
+//!
+//! Every request just compiles the document (and whatever it imports) from scratch. URSL files are small, and the
+//! compiler is fast enough that this is not worth caching.
+
+use ::ursl::*;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    self as lsp,
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, Request as _},
+    Url,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+use tree_sitter::{Node, Point};
+
+/// Intrinsics aren't defined anywhere, so they're not in [`CompileResult::functions`].
+const INTRINSICS: &[&str] = &[
+    "height",
+    "label",
+    "perm",
+    "const",
+    "ref",
+    "get",
+    "set",
+    "call",
+    "icall",
+    "tailcall",
+    "tailicall",
+    "extern",
+    "ret",
+    "halt",
+    "in",
+    "out",
+    "jump",
+    "branch",
+    "block",
+    "loop",
+    "if",
+];
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = lsp::ServerCapabilities {
+        text_document_sync: Some(lsp::TextDocumentSyncCapability::Kind(
+            lsp::TextDocumentSyncKind::FULL,
+        )),
+        hover_provider: Some(lsp::HoverProviderCapability::Simple(true)),
+        definition_provider: Some(lsp::OneOf::Left(true)),
+        completion_provider: Some(lsp::CompletionOptions::default()),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    Server {
+        connection: &connection,
+        documents: HashMap::new(),
+        published: HashMap::new(),
+    }
+    .run()?;
+    io_threads.join()?;
+    Ok(())
+}
+
+struct Server<'c> {
+    connection: &'c Connection,
+    documents: HashMap<Url, String>,
+    /// The other files each document has published diagnostics for, so they can be cleared again.
+    published: HashMap<Url, Vec<Url>>,
+}
+
+impl Server<'_> {
+    fn run(&mut self) -> Result<(), Box<dyn Error + Sync + Send>> {
+        for msg in &self.connection.receiver {
+            match msg {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    let response = self.handle_request(req);
+                    self.connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(not) => self.handle_notification(not)?,
+                Message::Response(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            HoverRequest::METHOD => dispatch::<HoverRequest>(req, |params| {
+                self.hover(params.text_document_position_params)
+            }),
+            GotoDefinition::METHOD => dispatch::<GotoDefinition>(req, |params| {
+                self.definition(params.text_document_position_params)
+            }),
+            Completion::METHOD => {
+                dispatch::<Completion>(req, |params| self.completion(params.text_document_position))
+            }
+            method => Err((
+                ErrorCode::MethodNotFound,
+                format!("Unknown method {method}"),
+            )),
+        };
+        match result {
+            Ok(value) => Response::new_ok(id, value),
+            Err((code, message)) => Response::new_err(id, code as i32, message),
+        }
+    }
+
+    fn handle_notification(
+        &mut self,
+        not: Notification,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp::DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                self.publish_diagnostics(uri)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp::DidChangeTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                // sync is FULL, so the last change is the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                self.publish_diagnostics(uri)?;
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp::DidCloseTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                for uri in self
+                    .published
+                    .remove(&uri)
+                    .into_iter()
+                    .flatten()
+                    .chain([uri])
+                {
+                    self.send_diagnostics(uri, Vec::new())?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn publish_diagnostics(&mut self, uri: Url) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let Some(text) = self.documents.get(&uri) else {
+            return Ok(());
+        };
+        let mut diagnostics = analyze(&uri, text, |analysis| {
+            let mut errors = analysis.errors;
+            if let Some(result) = analysis.result {
+                if !errors.iter().any(SourceError::is_error) {
+                    // some errors are only found while emitting, like --max-regs
+                    match panic::catch_unwind(AssertUnwindSafe(|| {
+                        emit(&mut io::sink(), &analysis.args, result)
+                    })) {
+                        Ok(Ok(emit_errors)) => errors.extend(emit_errors),
+                        Ok(Err(_)) => unreachable!("Writing to io::sink() can't fail"),
+                        Err(_) => errors.push(crashed()),
+                    }
+                }
+            }
+            let mut diagnostics = HashMap::<Url, Vec<lsp::Diagnostic>>::new();
+            for error in errors {
+                let (uri, range) = match &error.pos {
+                    Some(pos) if pos.unit.path() == PRELUDE_PATH => continue,
+                    Some(pos) if std::ptr::eq(pos.unit, analysis.doc) => {
+                        (uri.clone(), to_lsp_range(pos.unit.source(), pos.range))
+                    }
+                    Some(pos) => (
+                        file_url(pos.unit.path()),
+                        to_lsp_range(pos.unit.source(), pos.range),
+                    ),
+                    None => (uri.clone(), lsp::Range::default()),
+                };
+                diagnostics.entry(uri).or_default().push(lsp::Diagnostic {
+                    range,
+                    severity: Some(match error.severity {
+                        Severity::Error => lsp::DiagnosticSeverity::ERROR,
+                        Severity::Warning => lsp::DiagnosticSeverity::WARNING,
+                    }),
+                    code: error
+                        .lint
+                        .map(|lint| lsp::NumberOrString::String(lint.name().to_owned())),
+                    source: Some("ursl".to_owned()),
+                    message: error.message,
+                    ..Default::default()
+                });
+            }
+            diagnostics
+        });
+
+        let others = diagnostics
+            .keys()
+            .filter(|&other| *other != uri)
+            .cloned()
+            .collect::<Vec<_>>();
+        for old in self
+            .published
+            .insert(uri.clone(), others)
+            .into_iter()
+            .flatten()
+        {
+            diagnostics.entry(old).or_default();
+        }
+        diagnostics.entry(uri).or_default();
+        for (uri, diagnostics) in diagnostics {
+            self.send_diagnostics(uri, diagnostics)?;
+        }
+        Ok(())
+    }
+
+    fn send_diagnostics(
+        &self,
+        uri: Url,
+        diagnostics: Vec<lsp::Diagnostic>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let params = lsp::PublishDiagnosticsParams::new(uri, diagnostics, None);
+        self.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                PublishDiagnostics::METHOD.to_owned(),
+                params,
+            )))?;
+        Ok(())
+    }
+
+    fn hover(&self, params: lsp::TextDocumentPositionParams) -> Option<lsp::Hover> {
+        let uri = params.text_document.uri;
+        let text = self.documents.get(&uri)?;
+        let offset = from_lsp_position(text, params.position);
+        analyze(&uri, text, |analysis| {
+            let result = analysis.result.as_ref()?;
+            let mut sections = Vec::new();
+            if let Some((Symbol::Func(name), _)) = symbol_at(text, offset) {
+                if let Some(func) = result.functions().get(name) {
+                    let kind = match &func.body {
+                        FunctionBody::Ursl { .. } | FunctionBody::Deferred => "func".to_owned(),
+                        FunctionBody::Extern(convention, _) => {
+                            format!("extern \"{convention}\" func")
+                        }
+                        FunctionBody::Urcl { .. } | FunctionBody::Permutation(_) => {
+                            "inst".to_owned()
+                        }
+                    };
+                    sections.push(format!("```ursl\n{kind} {name} {}\n```", func.stack));
+                }
+            }
+            if let Some(entry) = instruction_at(result, analysis.doc, offset) {
+                sections.push(match entry.exit_height {
+                    Some(exit) => {
+                        format!("Stack height: {} before, {exit} after", entry.enter_height)
+                    }
+                    None => format!(
+                        "Stack height: {} before, nothing falls through",
+                        entry.enter_height
+                    ),
+                });
+            }
+            if sections.is_empty() {
+                return None;
+            }
+            Some(lsp::Hover {
+                contents: lsp::HoverContents::Markup(lsp::MarkupContent {
+                    kind: lsp::MarkupKind::Markdown,
+                    value: sections.join("\n\n---\n\n"),
+                }),
+                range: None,
+            })
+        })
+    }
+
+    fn definition(
+        &self,
+        params: lsp::TextDocumentPositionParams,
+    ) -> Option<lsp::GotoDefinitionResponse> {
+        let uri = params.text_document.uri;
+        let text = self.documents.get(&uri)?;
+        let offset = from_lsp_position(text, params.position);
+        let (symbol, _) = symbol_at(text, offset)?;
+        analyze(&uri, text, |analysis| {
+            let (unit, range) = match symbol {
+                Symbol::Func(name) => {
+                    let func = analysis.result.as_ref()?.functions().get(name)?;
+                    (func.unit, func.pos.range)
+                }
+                Symbol::Label(name) => {
+                    let result = analysis.result.as_ref()?;
+                    let (_, instructions) = ursl_functions(result, analysis.doc)
+                        .find(|(func, _)| func.node.byte_range().contains(&offset))?;
+                    let entry = instructions.iter().find(|entry| {
+                        matches!(entry.instruction, ursl::Instruction::Label(ursl::LocalLabel::Named(label)) if label == name)
+                    })?;
+                    let node = entry
+                        .node
+                        .child_by_field_name("label")
+                        .unwrap_or(entry.node);
+                    (analysis.doc, node.range())
+                }
+                Symbol::Data(name) => analysis.units.iter().rev().find_map(|unit| {
+                    unit.tree()
+                        .root_node()
+                        .children_by_field_name("data", &mut unit.tree().walk())
+                        .map(|node| node.field("label", unit))
+                        .find(|label| label.field("name", unit).text(unit) == name)
+                        .map(|label| (*unit, label.range()))
+                })?,
+            };
+            if unit.path() == PRELUDE_PATH {
+                // there's no file to go to
+                return None;
+            }
+            let uri = if std::ptr::eq(unit, analysis.doc) {
+                uri.clone()
+            } else {
+                file_url(unit.path())
+            };
+            Some(lsp::GotoDefinitionResponse::Scalar(lsp::Location {
+                uri,
+                range: to_lsp_range(unit.source(), range),
+            }))
+        })
+    }
+
+    fn completion(
+        &self,
+        params: lsp::TextDocumentPositionParams,
+    ) -> Option<lsp::CompletionResponse> {
+        let uri = params.text_document.uri;
+        let text = self.documents.get(&uri)?;
+        analyze(&uri, text, |analysis| {
+            let mut items = INTRINSICS
+                .iter()
+                .map(|&name| lsp::CompletionItem {
+                    label: name.to_owned(),
+                    kind: Some(lsp::CompletionItemKind::KEYWORD),
+                    detail: Some("intrinsic".to_owned()),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            if let Some(result) = &analysis.result {
+                // funcs are used with `call`, so only the instructions are completed here
+                items.extend(
+                    result
+                        .functions()
+                        .values()
+                        .filter(|func| !func.name.starts_with('$'))
+                        .map(|func| lsp::CompletionItem {
+                            label: func.name.to_owned(),
+                            kind: Some(lsp::CompletionItemKind::FUNCTION),
+                            detail: Some(format!("inst {} {}", func.name, func.stack)),
+                            ..Default::default()
+                        }),
+                );
+            }
+            Some(lsp::CompletionResponse::Array(items))
+        })
+    }
+}
+
+/// Everything known about a document after compiling it.
+struct Analysis<'a> {
+    args: Args,
+    units: &'a [&'a CompilationUnit<'a>],
+    doc: &'a CompilationUnit<'a>,
+    /// `None` if there are syntax errors, since the compiler can't deal with those.
+    result: Option<CompileResult<'a>>,
+    errors: Vec<SourceError<'a>>,
+}
+
+fn analyze<T>(uri: &Url, text: &str, f: impl for<'a> FnOnce(Analysis<'a>) -> T) -> T {
+    let path = uri
+        .to_file_path()
+        .unwrap_or_else(|()| PathBuf::from(uri.path()));
+    let files = load_sources_from(&path, text.to_owned());
+    let prelude = CompilationUnit::prelude();
+    let sources = files
+        .iter()
+        .map(CompilationUnit::from_file)
+        .collect::<Vec<_>>();
+    let doc = sources
+        .last()
+        .expect("The document itself is always loaded");
+
+    let mut errors = Vec::new();
+    for (file, unit) in files.iter().zip(sources.iter()) {
+        errors.extend(file.import_errors(unit));
+    }
+    for unit in sources.iter() {
+        syntax_errors(unit.tree().root_node(), unit, &mut errors);
+    }
+    let mut units = vec![&prelude];
+    units.extend(sources.iter());
+    let units = units.as_slice();
+
+    // a file without headers is probably imported by something else, so it's checked like a library
+    let library = doc
+        .tree()
+        .root_node()
+        .child_by_field_name("headers")
+        .is_none();
+    let args = Args {
+        no_main: library,
+        ..Args::default()
+    };
+    let result = if errors.iter().any(SourceError::is_error) {
+        None
+    } else {
+        match panic::catch_unwind(AssertUnwindSafe(|| compile(&args, units))) {
+            Ok((result, compile_errors)) => {
+                errors.extend(
+                    compile_errors
+                        .into_iter()
+                        .filter(|error| !(library && error.missing_header)),
+                );
+                Some(result)
+            }
+            Err(_) => {
+                errors.push(crashed());
+                None
+            }
+        }
+    };
+    f(Analysis {
+        args,
+        units,
+        doc,
+        result,
+        errors,
+    })
+}
+
+fn dispatch<R: lsp::request::Request>(
+    req: Request,
+    handler: impl FnOnce(R::Params) -> R::Result,
+) -> Result<serde_json::Value, (ErrorCode, String)> {
+    let (_, params) = req
+        .extract::<R::Params>(R::METHOD)
+        .map_err(|err| (ErrorCode::InvalidParams, format!("{err:?}")))?;
+    Ok(serde_json::to_value(handler(params)).expect("LSP types always serialize"))
+}
+
+fn crashed<'a>() -> SourceError<'a> {
+    err!(@ None, "The compiler crashed on this file. This is a bug in the compiler, not in your code")
+}
+
+fn syntax_errors<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+    errors: &mut Vec<SourceError<'a>>,
+) {
+    if node.is_error() {
+        errors.push(err!(@ Some(node.pos(unit)), "Syntax error"));
+    } else if node.is_missing() {
+        errors.push(err!(@ Some(node.pos(unit)), "Syntax error: missing {}", node.kind()));
+    } else if node.has_error() {
+        for child in node.children(&mut node.walk()) {
+            syntax_errors(child, unit, errors);
+        }
+    }
+}
+
+enum Symbol<'s> {
+    /// A `$func` or an instruction name, the same way they're keyed in [`CompileResult::functions`].
+    Func(&'s str),
+    /// `:label`, without the colon.
+    Label(&'s str),
+    /// `.data`, without the dot.
+    Data(&'s str),
+}
+
+/// Finds the name under the cursor by looking at the text around it. The grammar has a different node for every
+/// place a name can appear in, and the sigil already says what kind of name it is.
+fn symbol_at(text: &str, offset: usize) -> Option<(Symbol<'_>, usize)> {
+    let is_name = |c: char| c.is_alphanumeric() || c == '_';
+    let start = text[..offset]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !is_name(c))
+        .map_or(0, |(idx, c)| idx + c.len_utf8());
+    let end = text[offset..]
+        .find(|c: char| !is_name(c))
+        .map_or(text.len(), |idx| offset + idx);
+    if start == end {
+        return None;
+    }
+    let name = &text[start..end];
+    match text[..start].chars().last() {
+        Some('$') => Some((Symbol::Func(&text[start - 1..end]), start - 1)),
+        Some(':') => Some((Symbol::Label(name), start - 1)),
+        Some('.') => Some((Symbol::Data(name), start - 1)),
+        Some('%') => None,
+        _ => Some((Symbol::Func(name), start)),
+    }
+}
+
+fn ursl_functions<'r, 'a>(
+    result: &'r CompileResult<'a>,
+    doc: &'a CompilationUnit<'a>,
+) -> impl Iterator<Item = (&'r Function<'a>, &'r Vec<ursl::InstructionEntry<'a>>)> {
+    result
+        .functions()
+        .values()
+        .filter(move |func| std::ptr::eq(func.unit, doc))
+        .filter_map(|func| match &func.body {
+            FunctionBody::Ursl { instructions, .. } => Some((func, instructions)),
+            _ => None,
+        })
+}
+
+/// The innermost instruction at the cursor.
+fn instruction_at<'r, 'a>(
+    result: &'r CompileResult<'a>,
+    doc: &'a CompilationUnit<'a>,
+    offset: usize,
+) -> Option<&'r ursl::InstructionEntry<'a>> {
+    // inlined calls copy the callee's instructions into the caller, so only look in the func at the cursor
+    ursl_functions(result, doc)
+        .filter(|(func, _)| func.node.byte_range().contains(&offset))
+        .flat_map(|(_, instructions)| instructions)
+        .filter(|entry| entry.node.byte_range().contains(&offset))
+        .min_by_key(|entry| entry.node.byte_range().len())
+}
+
+fn file_url(path: &str) -> Url {
+    let path = Path::new(path);
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    Url::from_file_path(&path).unwrap_or_else(|()| {
+        Url::parse(&format!("file://{}", path.display())).expect("Just made a file URL")
+    })
+}
+
+// LSP counts columns in UTF-16 code units, but tree-sitter counts them in bytes
+
+fn to_lsp_position(text: &str, point: Point) -> lsp::Position {
+    let line = text.split('\n').nth(point.row).unwrap_or_default();
+    let column = line
+        .get(..point.column)
+        .map_or(point.column, |prefix| prefix.encode_utf16().count());
+    lsp::Position::new(point.row as u32, column as u32)
+}
+
+fn to_lsp_range(text: &str, range: tree_sitter::Range) -> lsp::Range {
+    lsp::Range::new(
+        to_lsp_position(text, range.start_point),
+        to_lsp_position(text, range.end_point),
+    )
+}
+
+/// The byte offset of a position in the text.
+fn from_lsp_position(text: &str, position: lsp::Position) -> usize {
+    let mut offset = 0;
+    for (row, line) in text.split('\n').enumerate() {
+        if row == position.line as usize {
+            let mut units = 0;
+            for (idx, c) in line.char_indices() {
+                if units >= position.character as usize {
+                    return offset + idx;
+                }
+                units += c.len_utf16();
+            }
+            return offset + line.len();
+        }
+        offset += line.len() + 1;
+    }
+    text.len()
+}
@@ -0,0 +1,321 @@
+use super::*;
+use num::{BigUint, Zero};
+use peephole::{Line, Program};
+use std::{
+    cmp,
+    fmt::{self, Display, Formatter},
+};
+
+/// Why the callstack can't be bounded, and the chain of calls from `$main` that gets there.
+pub struct Unbounded<'a> {
+    pub chain: Vec<&'a str>,
+    pub reason: Reason,
+}
+
+pub enum Reason {
+    /// The last func in the chain calls one that's already in the chain.
+    Recursion,
+    /// The last func in the chain calls (or tail calls) a func pointer, which could be anything.
+    Icall,
+    /// The last func in the chain is an extern func, whose URCL isn't part of the output.
+    Extern,
+    /// The last func in the chain pushes more every time around a loop.
+    Loop,
+}
+
+impl<'a> Unbounded<'a> {
+    /// The func where it happens, to point the error at.
+    pub fn func<'r>(
+        &self,
+        functions: &'r BTreeMap<&'a str, Function<'a>>,
+    ) -> Option<&'r Function<'a>> {
+        self.chain.last().and_then(|name| functions.get(name))
+    }
+}
+
+impl Display for Unbounded<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let chain = self.chain.join(" -> ");
+        match self.reason {
+            Reason::Recursion => write!(f, "it's recursive: {chain}"),
+            Reason::Icall => write!(
+                f,
+                "it uses icall, which could call anything: {chain} -> icall"
+            ),
+            Reason::Loop => write!(f, "the callstack grows every time around a loop: {chain}"),
+            Reason::Extern => write!(
+                f,
+                "it calls an extern func, which could use any amount of callstack: {chain}"
+            ),
+        }
+    }
+}
+
+/// The most words of callstack the emitted program can ever use, starting from the top of the program.
+///
+/// This follows every path through the URCL of each func, counting `PSH`, `POP` and `SP` arithmetic, and adds up
+/// the deepest chain of `CAL`s. That covers return addresses, arguments, locals, registers saved around calls and
+/// spilled stack items, since they're all written the same way. It's done on the optimized output so that nothing
+/// the peephole pass removed is counted.
+pub fn max_depth<'a>(
+    program: &Program,
+    functions: &BTreeMap<&'a str, Function<'a>>,
+    bits: u64,
+) -> Result<usize, Unbounded<'a>> {
+    let labels = program
+        .0
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, line)| match line {
+            Line::Label(label) => Some((label.as_str(), idx)),
+            _ => None,
+        })
+        .collect();
+    // extern funcs are called by their own label, not the mangled name
+    let funcs = functions
+        .values()
+        .map(|func| match func.body {
+            FunctionBody::Extern(_, ref label) => (format!(".{label}"), func.name),
+            _ => (format!(".{}", mangle::function_name(func.name)), func.name),
+        })
+        .collect();
+    let mut analysis = Analysis {
+        lines: &program.0,
+        functions,
+        labels,
+        funcs,
+        bits,
+        done: HashMap::new(),
+        active: Vec::new(),
+    };
+    analysis.walk(0)
+}
+
+struct Analysis<'p, 'a> {
+    lines: &'p [Line],
+    functions: &'p BTreeMap<&'a str, Function<'a>>,
+    labels: HashMap<&'p str, usize>,
+    funcs: HashMap<String, &'a str>,
+    bits: u64,
+    done: HashMap<&'a str, usize>,
+    /// The chain of calls currently being walked, to find recursion.
+    active: Vec<&'a str>,
+}
+
+impl<'p, 'a> Analysis<'p, 'a> {
+    fn unbounded(&self, reason: Reason) -> Unbounded<'a> {
+        Unbounded {
+            chain: self.active.clone(),
+            reason,
+        }
+    }
+
+    /// The deepest the callstack gets while running a func, not counting its own return address.
+    fn func(&mut self, name: &'a str) -> Result<usize, Unbounded<'a>> {
+        if let Some(&depth) = self.done.get(name) {
+            return Ok(depth);
+        }
+        if self.active.contains(&name) {
+            let mut unbounded = self.unbounded(Reason::Recursion);
+            unbounded.chain.push(name);
+            return Err(unbounded);
+        }
+        if let Some(FunctionBody::Extern(..)) = self.functions.get(name).map(|func| &func.body) {
+            let mut unbounded = self.unbounded(Reason::Extern);
+            unbounded.chain.push(name);
+            return Err(unbounded);
+        }
+        let label = format!(".{}", mangle::function_name(name));
+        let Some(&start) = self.labels.get(label.as_str()) else {
+            // not emitted, so it's never called either
+            return Ok(0);
+        };
+        self.active.push(name);
+        let depth = self.walk(start);
+        self.active.pop();
+        let depth = depth?;
+        self.done.insert(name, depth);
+        Ok(depth)
+    }
+
+    fn walk(&mut self, start: usize) -> Result<usize, Unbounded<'a>> {
+        // the height every line was first reached with. the compiler always reaches a label with the same height
+        let mut seen = HashMap::new();
+        let mut pending = vec![(start, 0usize)];
+        let mut max = 0;
+        while let Some((mut idx, mut height)) = pending.pop() {
+            while let Some(line) = self.lines.get(idx) {
+                match seen.insert(idx, height) {
+                    Some(prev) if prev < height => return Err(self.unbounded(Reason::Loop)),
+                    Some(_) => break,
+                    None => (),
+                }
+                max = cmp::max(max, height);
+                let (op, operands): (&str, Vec<&str>) = match line {
+                    Line::Instruction { op, operands } => {
+                        (op, operands.iter().map(String::as_str).collect())
+                    }
+                    // splitting is fine for the op and the first operand, since those never have quotes
+                    Line::Raw(text) => {
+                        let mut tokens = text.split_whitespace();
+                        (tokens.next().unwrap_or_default(), tokens.collect())
+                    }
+                    Line::Label(_) | Line::Comment(_) => {
+                        idx += 1;
+                        continue;
+                    }
+                };
+                let words = |n: &str| n.parse::<usize>().unwrap_or_default();
+                match (op, operands.as_slice()) {
+                    ("PSH", [_]) => height += 1,
+                    ("POP", [_]) => height = height.saturating_sub(1),
+                    ("SUB", ["SP", "SP", n]) => height += words(n),
+                    ("ADD", ["SP", "SP", n]) => height = height.saturating_sub(words(n)),
+                    ("CAL", [dest]) => {
+                        let Some(&callee) = self.funcs.get(*dest) else {
+                            return Err(self.unbounded(Reason::Icall));
+                        };
+                        // the return address is on top of everything else
+                        max = cmp::max(max, height + 1 + self.func(callee)?);
+                    }
+                    ("RET" | "HLT", _) => break,
+                    ("JMP", [dest]) => match self.target(idx, dest) {
+                        Some(dest) => {
+                            idx = dest;
+                            continue;
+                        }
+                        // a jump to a register is a tailicall, which could jump anywhere
+                        None => return Err(self.unbounded(Reason::Icall)),
+                    },
+                    (op, [dest, inputs @ ..])
+                        if fold::condition(op, &vec![BigUint::zero(); inputs.len()], self.bits)
+                            .is_some() =>
+                    {
+                        if let Some(dest) = self.target(idx, dest) {
+                            pending.push((dest, height));
+                        }
+                    }
+                    _ => (),
+                }
+                idx += 1;
+            }
+        }
+        Ok(max)
+    }
+
+    /// The line a jump or branch goes to, for labels and `~+n`.
+    fn target(&self, idx: usize, dest: &str) -> Option<usize> {
+        if dest.starts_with('.') {
+            return self.labels.get(dest).copied();
+        }
+        let offset = dest.strip_prefix('~')?.parse::<isize>().ok()?;
+        // relative jumps count instructions, not lines
+        let is_instruction = |line: &Line| matches!(line, Line::Instruction { .. } | Line::Raw(_));
+        if offset >= 0 {
+            (idx..self.lines.len())
+                .filter(|&i| is_instruction(&self.lines[i]))
+                .nth(offset as usize)
+        } else {
+            (0..idx)
+                .rev()
+                .filter(|&i| is_instruction(&self.lines[i]))
+                .nth(offset.unsigned_abs() - 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::*;
+
+    /// Checks the computed `MINSTACK` against the emulator, which should run with exactly that much and overflow with
+    /// any less. Returns what the program wrote.
+    #[track_caller]
+    fn assert_exact_minstack(source: &str) -> String {
+        let urcl = compile_source(source).unwrap();
+        let minstack = urcl
+            .lines()
+            .find_map(|line| line.strip_prefix("MINSTACK "))
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let output = emulate_urcl(&urcl).unwrap();
+        let less = urcl.replace(
+            &format!("MINSTACK {minstack}\n"),
+            &format!("MINSTACK {}\n", minstack - 1),
+        );
+        let err = emulate_urcl(&less).unwrap_err();
+        assert!(err.contains("Stack overflow"), "{err} in:\n{less}");
+        output
+    }
+
+    #[test]
+    fn nested_calls() {
+        let output = assert_exact_minstack(&format!(
+            "{HEADERS}
+            func $leaf 2 -> 1 + 1 {{ get 0 get 1 add set 2 get 2 ret }}
+            func $mid 1 -> 1 {{ get 0 const 1 call $leaf ret }}
+            func $main {{ const 5 call $mid const 1 call $mid add out %numb }}"
+        ));
+        assert_eq!(output, "8");
+    }
+
+    #[test]
+    fn tail_recursion_is_bounded() {
+        let output = assert_exact_minstack(&format!(
+            "{HEADERS}
+            func $count 1 -> 0 {{
+                get 0 out %numb
+                get 0 bool if {{ get 0 dec tailcall $count }}
+            }}
+            func $main {{ const 3 call $count }}"
+        ));
+        assert_eq!(output, "3210");
+    }
+
+    #[test]
+    fn declared_minstack_is_checked() {
+        assert_error(
+            &format!(
+                "{HEADERS}minstack 1
+                func $f 0 -> 0 {{ }}
+                func $main {{ call $f }}"
+            ),
+            "The `minstack` header is 1, but the callstack can get",
+        );
+    }
+
+    #[test]
+    fn recursion_and_icall_are_unbounded() {
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 0 -> 0 {{ call $f }}
+                func $main {{ call $f }}"
+            ),
+            "Can't compute the `minstack` header, because it's recursive: $main -> $f",
+        );
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 0 -> 0 {{ }}
+                func $main {{ const $f icall 0 -> 0 }}"
+            ),
+            "it uses icall, which could call anything: $main -> icall",
+        );
+    }
+
+    #[test]
+    fn extern_call_is_unbounded() {
+        for convention in ["URSL", "URCL++"] {
+            assert_error(
+                &format!(
+                    "{HEADERS}
+                    extern \"{convention}\" func $ext 0 -> 0;
+                    func $main {{ call $ext }}"
+                ),
+                "it calls an extern func, which could use any amount of callstack: $main -> $ext",
+            );
+        }
+    }
+}
@@ -1,10 +1,41 @@
 use super::*;
 use num::BigUint;
-use std::fmt::{self, Display, Formatter};
+use std::{
+    fmt::{self, Display, Formatter},
+    iter,
+};
 
 pub struct SourceError<'a> {
     pub pos: Option<Position<'a>>,
     pub message: String,
+    pub severity: Severity,
+    /// The lint that produced this, if it's not a hard error.
+    pub lint: Option<lints::Lint>,
+    /// Whether this is about a header that the main file doesn't declare, which doesn't matter for a file that's only
+    /// ever imported.
+    pub missing_header: bool,
+}
+
+impl SourceError<'_> {
+    /// Whether this should fail the compilation. Warnings don't.
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
 }
 
 #[macro_export]
@@ -29,6 +60,9 @@ macro_rules! err {
         SourceError {
             pos: $pos,
             message: format!($($t)*),
+            severity: Severity::Error,
+            lint: None,
+            missing_header: false,
         }
     };
     ($errors:expr; None$(; $value:expr)?, $($t:tt)*) => {{
@@ -41,6 +75,24 @@ macro_rules! err {
     }};
 }
 
+/// Like `err!`, but for a lint. Depending on `-A`, `-W` and `-D`, it's pushed as a warning, an error, or not at all.
+#[macro_export]
+macro_rules! lint {
+    ($errors:expr; $args:expr; $lint:expr; @ $pos:expr, $($t:tt)*) => {{
+        let lint = $lint;
+        if let Some(severity) = $args.lint_level(lint) {
+            $errors.push(SourceError {
+                severity,
+                lint: Some(lint),
+                ..err!(@ $pos, $($t)*)
+            });
+        }
+    }};
+    ($errors:expr; $args:expr; $lint:expr; $unit:expr; $node:expr, $($t:tt)*) => {
+        lint!($errors; $args; $lint; @ Some($node.pos($unit)), $($t)*)
+    };
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct StackBehaviour {
     pub input: usize,
@@ -84,30 +136,83 @@ pub enum FunctionBody<'a> {
     Ursl {
         locals: usize,
         instructions: Vec<ursl::InstructionEntry<'a>>,
+        /// For `export "convention" func`, the calling convention it's emitted with and the extra label it gets.
+        export: Option<(CallingConvention<'a>, Cow<'a, str>)>,
+        /// `inline func`, which is spliced into every `call` to it by ``ursl::inline_calls``.
+        inline: bool,
     },
     Urcl {
         overloads: Vec<UrclMainBody<'a>>,
         branch: Option<UrclBranchBody<'a>>,
+        /// The URCL instruction this is equivalent to, so it can be evaluated at compile time when all inputs are known.
+        fold: Option<&'a str>,
     },
     Permutation(Permutation),
-    Extern(CallingConvention, Cow<'a, str>),
+    Extern(CallingConvention<'a>, Cow<'a, str>),
     Deferred,
 }
 
+/// Everything ``write_call`` needs to know to call a func, either one of the builtin ones or declared with
+/// `convention "name" { ... }`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CallingConvention<'a> {
+    pub name: &'a str,
+    /// Whether the last arg is pushed first, so the first arg ends up on top.
+    pub reversed_args: bool,
+    /// The register the first return value is in. The rest follow it.
+    pub returns: usize,
+    /// Whether more than one word can be returned.
+    pub multiple_returns: bool,
+    /// Whether the caller saves the regs it's using around the call. Otherwise, the callee keeps every reg intact
+    /// except the ones it returns in.
+    pub caller_saves: bool,
+    /// Whether the caller pops the args after the call. Otherwise, the callee does.
+    pub caller_cleanup: bool,
+    pub mangling: Mangling<'a>,
+}
+
+/// How the label of an extern func is made from its name, when it's not given.
 #[derive(Clone, Copy, PartialEq, Eq)]
-pub enum CallingConvention {
-    URSL,
-    URCLpp,
+pub enum Mangling<'a> {
+    Ursl,
+    /// The name as written.
+    Name,
+    /// From the param and return types, see ``mangle::hexagn_function_name``.
     Hexagn,
+    /// The name without the `$`, after a prefix.
+    Prefix(&'a str),
 }
 
-impl Display for CallingConvention {
+// named like the enum variants they used to be
+#[allow(non_upper_case_globals)]
+impl CallingConvention<'static> {
+    pub const URSL: Self = CallingConvention {
+        name: "URSL",
+        reversed_args: true,
+        returns: 1,
+        multiple_returns: true,
+        caller_saves: true,
+        caller_cleanup: true,
+        mangling: Mangling::Ursl,
+    };
+    pub const URCLpp: Self = CallingConvention {
+        name: "URCL++",
+        mangling: Mangling::Name,
+        ..Self::URSL
+    };
+    pub const Hexagn: Self = CallingConvention {
+        name: "Hexagn",
+        returns: 2,
+        multiple_returns: false,
+        mangling: Mangling::Hexagn,
+        ..Self::URSL
+    };
+    pub const BUILTIN: [Self; 3] = [Self::URSL, Self::URCLpp, Self::Hexagn];
+}
+
+impl Display for CallingConvention<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            CallingConvention::URSL => write!(f, "URSL"),
-            CallingConvention::URCLpp => write!(f, "URCL++"),
-            CallingConvention::Hexagn => write!(f, "Hexagn"),
-        }
+        write!(f, "{}", self.name)
     }
 }
 
@@ -179,7 +284,7 @@ pub enum Literal<'a> {
     CharEscape(CharEscape),
     Macro(&'a str),
     Num(BigUint),
-    Mem(u64),
+    Mem(BigUint),
     Label(&'a str),
     Func(&'a str),
 }
@@ -250,29 +355,36 @@ pub fn parse_literal<'a>(
                 _ => unknown_node(node, unit),
             }
         }
-        "number" => Literal::Num(parse_num(node.text(unit))),
+        "number" => Literal::Num(parse_num(node, unit).extend_into(&mut errors)),
         "macro" => Literal::Macro(node.field("name", unit).text(unit)),
         "data_label" => Literal::Label(node.field("name", unit).text(unit)),
         "function_name" => Literal::Func(node.text(unit)),
-        "mem" => Literal::Mem(node.field("index", unit).text(unit).parse().unwrap()),
+        "mem" => Literal::Mem(parse_num(node.field("index", unit), unit).extend_into(&mut errors)),
         _ => unknown_node(node, unit),
     };
     (literal, errors)
 }
 
-pub fn parse_num<T: Num>(text: &str) -> T
+pub fn parse_num<'a, T: Num + Default>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (T, Vec<SourceError<'a>>)
 where
-    T::FromStrRadixErr: Debug,
+    T::FromStrRadixErr: Display,
 {
-    if text.starts_with("0x") {
-        T::from_str_radix(&text[2..], 16).unwrap()
+    let mut errors = Vec::new();
+    let text = node.text(unit);
+    let num = if text.starts_with("0x") {
+        T::from_str_radix(&text[2..], 16)
     } else if text.starts_with("0b") {
-        T::from_str_radix(&text[2..], 2).unwrap()
+        T::from_str_radix(&text[2..], 2)
     } else if text.starts_with("0o") {
-        T::from_str_radix(&text[2..], 8).unwrap()
+        T::from_str_radix(&text[2..], 8)
     } else {
-        T::from_str_radix(text, 10).unwrap()
+        T::from_str_radix(text, 10)
     }
+    .unwrap_or_else(|err| err!(errors; unit; node; T::default(), "Invalid number {text}: {err}"));
+    (num, errors)
 }
 
 pub fn parse_char_escape<'a>(
@@ -329,7 +441,7 @@ pub fn lower_literal<'a>(
     unit: &'a CompilationUnit<'a>,
 ) -> (Literal<'a>, Vec<SourceError<'a>>) {
     let mut errors = Vec::new();
-    if args.emit_chars_literally {
+    if args.emit_chars_literally || args.emit_chars_as_numbers {
         if let Literal::CharEscape(escape) = element {
             element = Literal::Char(lower_char_escape(escape).unwrap_or_else(|| {
                 err!(errors; unit; node, "Invalid char escape: {}", escape);
@@ -343,8 +455,12 @@ pub fn lower_literal<'a>(
         }
     }
     if let Literal::Num(ref n) = element {
-        if n.bits() > headers.bits {
-            err!(errors; unit; node, "This literal requires {} bits, but the bits header is set to {}.", n.bits(), headers.bits);
+        let min = headers.bits.minimum();
+        if n.bits() > min {
+            match headers.bits {
+                Bits::Any => err!(errors; unit; node, "This literal requires {} bits, but without a bits header it must fit in {min}.", n.bits()),
+                bits => err!(errors; unit; node, "This literal requires {} bits, but the bits header is set to {bits}.", n.bits()),
+            }
         }
     }
     (element, errors)
@@ -424,13 +540,25 @@ impl<'a, T> SourceErrors<'a> for (T, Vec<SourceError<'a>>) {
     }
 }
 
+/// The last field is how many registers at the bottom are reserved for args passed in registers, which the stack
+/// never uses.
 #[derive(Clone)]
-pub struct RegisterAllocation<'a>(Vec<AllocationSlot<'a>>);
+pub struct RegisterAllocation<'a>(Vec<AllocationSlot<'a>>, Option<RegisterLimit>, usize);
+
+/// How many registers the allocation may use, with `--max-regs`.
+#[derive(Clone, Copy)]
+pub struct RegisterLimit {
+    pub max: usize,
+    /// How many stack items are kept in registers at labels. This leaves room for the inputs of branches, which are normalized with their inputs on top.
+    pub at_labels: usize,
+}
 
 #[derive(Clone)]
 pub enum AllocationSlot<'a> {
     Register(usize),
     Literal(Literal<'a>),
+    /// Pushed to the callstack to free up a register. Only the bottom of the stack is ever spilled, in order, so they can be popped back in reverse.
+    Spilled,
 }
 
 impl Display for AllocationSlot<'_> {
@@ -438,17 +566,109 @@ impl Display for AllocationSlot<'_> {
         match self {
             Self::Register(reg) => write!(f, "${reg}"),
             Self::Literal(lit) => write!(f, "{lit}"),
+            Self::Spilled => write!(f, "[spilled]"),
         }
     }
 }
 
 impl<'a> RegisterAllocation<'a> {
-    pub fn new() -> Self {
-        Self(vec![])
+    pub fn new(limit: Option<RegisterLimit>, reserved: usize) -> Self {
+        Self(vec![], limit, reserved)
     }
 
-    pub fn normal(height: usize) -> Self {
-        Self((1..=height).map(AllocationSlot::Register).collect())
+    pub fn normal(height: usize, limit: Option<RegisterLimit>, reserved: usize) -> Self {
+        let spilled = limit.map_or(0, |limit| height.saturating_sub(limit.at_labels));
+        Self(
+            iter::repeat_n(AllocationSlot::Spilled, spilled)
+                .chain((reserved + 1..=reserved + height - spilled).map(AllocationSlot::Register))
+                .collect(),
+            limit,
+            reserved,
+        )
+    }
+
+    pub fn limit(&self) -> Option<RegisterLimit> {
+        self.1
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.2
+    }
+
+    /// The same allocation, but allowed to use any amount of registers, including the ones reserved for args.
+    pub fn without_limit(&self) -> Self {
+        Self(self.0.clone(), None, 0)
+    }
+
+    pub fn height(&self) -> usize {
+        self.0.len()
+    }
+
+    /// How many items at the bottom of the stack are spilled to the callstack. Anything that addresses relative to SP must be offset by this.
+    pub fn spilled(&self) -> usize {
+        self.0
+            .iter()
+            .take_while(|slot| matches!(slot, AllocationSlot::Spilled))
+            .count()
+    }
+
+    /// How many more registers can be used without going over the limit.
+    pub fn free_regs(&self) -> usize {
+        match self.1 {
+            Some(limit) => limit.max.saturating_sub(
+                self.all_used_regs()
+                    .into_iter()
+                    .filter(|&reg| reg <= limit.max)
+                    .count(),
+            ),
+            None => usize::MAX,
+        }
+    }
+
+    /// Spills the lowest item that is not spilled yet, if it is below `below`. Returns whether anything was spilled.
+    pub fn spill_one(&mut self, f: &mut impl Write, below: usize) -> io::Result<bool> {
+        let spilled = self.spilled();
+        if spilled >= below.min(self.0.len()) {
+            return Ok(false);
+        }
+        writeln!(f, "PSH {}", self.0[spilled])?;
+        self.0[spilled] = AllocationSlot::Spilled;
+        Ok(true)
+    }
+
+    /// Spills items below `below` until `count` more registers can be used. Returns whether that was possible.
+    pub fn make_room(
+        &mut self,
+        f: &mut impl Write,
+        count: usize,
+        below: usize,
+    ) -> io::Result<bool> {
+        while self.free_regs() < count {
+            if !self.spill_one(f, below)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Pops spilled items back into registers until the top `count` items are not spilled.
+    pub fn reload_top(
+        &mut self,
+        f: &mut impl Write,
+        count: usize,
+        max_regs: &mut usize,
+    ) -> io::Result<()> {
+        let keep = self.0.len().saturating_sub(count);
+        while self.spilled() > keep {
+            let i = self.spilled() - 1;
+            let reg = self.next_reg();
+            if let AllocationSlot::Register(reg) = reg {
+                *max_regs = (*max_regs).max(reg);
+            }
+            writeln!(f, "POP {reg}")?;
+            self.0[i] = reg;
+        }
+        Ok(())
     }
 
     pub fn offset(&mut self, offset: usize) {
@@ -459,10 +679,11 @@ impl<'a> RegisterAllocation<'a> {
         }
     }
 
-    pub fn offset_except(&mut self, offset: usize, dont_offset: usize) {
+    /// Offsets only the regs from `first` up, for when something is put in the regs right below them.
+    pub fn offset_from(&mut self, first: usize, offset: usize) {
         for reg in self.0.iter_mut() {
             if let AllocationSlot::Register(reg) = reg {
-                if *reg != dont_offset {
+                if *reg >= first {
                     *reg += offset;
                 }
             }
@@ -491,6 +712,19 @@ impl<'a> RegisterAllocation<'a> {
         used
     }
 
+    /// Like [`RegisterAllocation::all_used_regs`], but only the registers of slots that are marked as live.
+    pub fn live_regs(&self, live: &[bool]) -> Vec<usize> {
+        let mut used = Vec::with_capacity(self.0.len());
+        for (i, slot) in self.0.iter().enumerate() {
+            if let AllocationSlot::Register(reg) = slot {
+                if live.get(i).copied().unwrap_or(false) && !used.contains(reg) {
+                    used.push(*reg);
+                }
+            }
+        }
+        used
+    }
+
     pub fn get(&self, length: usize) -> &[AllocationSlot<'a>] {
         &self.0[(self.0.len() - length)..]
     }
@@ -509,7 +743,7 @@ impl<'a> RegisterAllocation<'a> {
                 .iter()
                 .filter(|r| match r {
                     AllocationSlot::Register(r) => *r == reg,
-                    AllocationSlot::Literal(_) => false,
+                    AllocationSlot::Literal(_) | AllocationSlot::Spilled => false,
                 })
                 .count()
                 == 1
@@ -518,7 +752,7 @@ impl<'a> RegisterAllocation<'a> {
     pub fn next_reg(&self) -> AllocationSlot<'a> {
         // pretty sure this is O(n^2) and can be improved, but i have no idea how to do so.
         // (the difficult part is reusing old regs, so max value is no good)
-        for i in 1.. {
+        for i in self.2 + 1.. {
             // bullshit impl of contains that does not rely on an impl PartialEq for Literal
             if self
                 .0
@@ -571,6 +805,16 @@ impl<'a> RegisterAllocation<'a> {
         top_literals_unchanged: usize,
     ) -> io::Result<()> {
         let length = self.0.len() - top_literals_unchanged;
+        let first = self.2 + 1;
+        if let Some(limit) = self.1 {
+            // with a register limit, the normal form has everything but the top few items spilled
+            let target = length.saturating_sub(limit.at_labels);
+            while self.spilled() < target {
+                self.spill_one(f, length)?;
+            }
+            self.reload_top(f, self.0.len() - target, max_regs)?;
+        }
+        let spilled = self.spilled();
         let mut changes = self
             .0
             .iter()
@@ -578,7 +822,7 @@ impl<'a> RegisterAllocation<'a> {
             .take(length)
             .filter_map(|(dest, slot)| {
                 if let AllocationSlot::Register(src) = slot {
-                    Some((*src, dest + 1))
+                    Some((*src, dest + first - spilled))
                 } else {
                     None
                 }
@@ -599,7 +843,7 @@ impl<'a> RegisterAllocation<'a> {
                         *src = *dest;
                         None
                     } else {
-                        Some((*src, i + length + 1))
+                        Some((*src, i + length + first - spilled))
                     }
                 } else {
                     None
@@ -610,7 +854,9 @@ impl<'a> RegisterAllocation<'a> {
             .into_iter()
             .filter(|(src, dest)| src != dest)
             .collect();
-        let circular_temp_reg = length + top_changes.len() + 1;
+        let circular_temp_reg = length - spilled + top_changes.len() + first;
+        // if the temporary register would go over the limit, rotate through the callstack instead
+        let circular_through_stack = self.1.is_some_and(|limit| circular_temp_reg > limit.max);
         changes.extend(top_changes);
         let literals = self
             .0
@@ -619,7 +865,7 @@ impl<'a> RegisterAllocation<'a> {
             .take(length)
             .filter_map(|(dest, slot)| {
                 if let AllocationSlot::Literal(lit) = slot {
-                    Some((lit, dest + 1))
+                    Some((lit, dest + first - spilled))
                 } else {
                     None
                 }
@@ -656,8 +902,14 @@ impl<'a> RegisterAllocation<'a> {
             for i in 1..circular.len() {
                 let src = circular[i];
                 let dest = circular[i - 1];
-                *max_regs = (*max_regs).max(src).max(dest);
-                result = result.and_then(|()| writeln!(f, "MOV ${} ${}", dest, src));
+                if circular_through_stack && i == 1 {
+                    result = result.and_then(|()| writeln!(f, "PSH ${}", src));
+                } else if circular_through_stack && i == circular.len() - 1 {
+                    result = result.and_then(|()| writeln!(f, "POP ${}", dest));
+                } else {
+                    *max_regs = (*max_regs).max(src).max(dest);
+                    result = result.and_then(|()| writeln!(f, "MOV ${} ${}", dest, src));
+                }
             }
         }
 
@@ -666,8 +918,8 @@ impl<'a> RegisterAllocation<'a> {
             result = result.and_then(|()| writeln!(f, "IMM ${} {}", dest, src));
         }
 
-        for i in 0..length {
-            self.0[i] = AllocationSlot::Register(i + 1);
+        for i in spilled..length {
+            self.0[i] = AllocationSlot::Register(i + first - spilled);
         }
         for i in length..self.0.len() {
             if ignored_on_purpose.contains(&i) {
@@ -675,7 +927,7 @@ impl<'a> RegisterAllocation<'a> {
             }
             // after `length`, literals are not normalized
             if let AllocationSlot::Register(_) = self.0[i] {
-                self.0[i] = AllocationSlot::Register(i + 1);
+                self.0[i] = AllocationSlot::Register(i + first - spilled);
             }
         }
         if args.verbose {
@@ -702,6 +954,20 @@ enum StringSegment<'a> {
 #[derive(Clone)]
 pub struct SyntaxString<'a>(Vec<(Node<'a>, StringSegment<'a>)>);
 
+impl SyntaxString<'_> {
+    /// The actual characters of the string, with escapes resolved. Invalid escapes are kept as the escaped char.
+    pub fn chars(&self) -> Vec<char> {
+        let mut chars = Vec::new();
+        for (_, segment) in &self.0 {
+            match *segment {
+                StringSegment::Literal(lit) => chars.extend(lit.chars()),
+                StringSegment::Escape(esc) => chars.push(lower_char_escape(esc).unwrap_or(esc.into())),
+            }
+        }
+        chars
+    }
+}
+
 impl Display for SyntaxString<'_> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         self.0
@@ -763,34 +1029,101 @@ pub fn parse_string<'a>(
 
 pub fn parse_call_convention<'a>(
     node: Node<'a>,
+    conventions: &HashMap<&'a str, CallingConvention<'a>>,
     unit: &'a CompilationUnit<'a>,
-) -> (CallingConvention, Vec<SourceError<'a>>) {
+) -> (CallingConvention<'a>, Vec<SourceError<'a>>) {
     let mut errors = Vec::new();
-    let convention = match parse_string(node, unit)
+    let name = parse_string(node, unit)
         .extend_into(&mut errors)
-        .to_string()
-        .as_str()
-    {
-        "URSL" => CallingConvention::URSL,
-        "URCL++" => CallingConvention::URCLpp,
-        "Hexagn" => CallingConvention::Hexagn,
-        unknown => match unknown.to_lowercase().as_str() {
-            "ursl" => err!(
-                errors; unit; node; CallingConvention::URSL,
-                "Unknown calling convention: \"{unknown}\" (did you mean \"URSL\"?)"
-            ),
-            "urcl++" | "urclpp" => err!(
-                errors; unit; node; CallingConvention::URCLpp,
-                "Unknown calling convention: \"{unknown}\" (did you mean \"URCL++\"?)"
-            ),
-            "hexagn" => err!(
-                errors; unit; node; CallingConvention::Hexagn,
-                "Unknown calling convention: \"{unknown}\" (did you mean \"Hexagn\"?)"
-            ),
-            _ => {
-                err!(errors; unit; node; CallingConvention::URSL, "Unknown calling convention: \"{unknown}\"")
+        .to_string();
+    let known = || CallingConvention::BUILTIN.into_iter().chain(conventions.values().copied());
+    let convention = match known().find(|convention| convention.name == name) {
+        Some(convention) => convention,
+        None => {
+            let lower = name.to_lowercase();
+            let similar = known().find(|convention| {
+                convention.name.to_lowercase() == lower
+                    || (*convention == CallingConvention::URCLpp && lower == "urclpp")
+            });
+            match similar {
+                Some(convention) => err!(
+                    errors; unit; node; convention,
+                    "Unknown calling convention: \"{name}\" (did you mean \"{convention}\"?)"
+                ),
+                None => {
+                    err!(errors; unit; node; CallingConvention::URSL, "Unknown calling convention: \"{name}\"")
+                }
             }
-        },
+        }
     };
     (convention, errors)
 }
+
+/// A string that's used as-is, like names and label prefixes, where escapes aren't useful.
+fn parse_plain_string<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (&'a str, Vec<SourceError<'a>>) {
+    let mut errors = Vec::new();
+    let string = parse_string(node, unit).extend_into(&mut errors);
+    let text = match string.0.as_slice() {
+        [] => "",
+        [(_, StringSegment::Literal(text))] => text,
+        _ => err!(errors; unit; node; "", "This string can't contain escapes"),
+    };
+    (text, errors)
+}
+
+/// A `convention "name" { ... }` declaration. Anything left out is the same as URCL++.
+pub fn parse_convention<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (CallingConvention<'a>, Vec<SourceError<'a>>) {
+    let mut errors = Vec::new();
+    let mut convention = CallingConvention {
+        name: parse_plain_string(node.field("name", unit), unit).extend_into(&mut errors),
+        ..CallingConvention::URCLpp
+    };
+    for property in node.children_by_field_name("property", &mut unit.tree.walk()) {
+        let key = property.field("key", unit).text(unit);
+        let value = property.child_by_field_name("value");
+        let text = value.map(|value| value.text(unit));
+        match (key, text) {
+            ("args", Some("reversed-push")) => convention.reversed_args = true,
+            ("args", Some("push")) => convention.reversed_args = false,
+            ("returns", Some(returns)) => {
+                let (first, multiple) = match returns.strip_suffix("..") {
+                    Some(first) => (first, true),
+                    None => (returns, false),
+                };
+                match first.strip_prefix('$').and_then(|reg| reg.parse().ok()) {
+                    Some(reg) if reg > 0 => {
+                        convention.returns = reg;
+                        convention.multiple_returns = multiple;
+                    }
+                    _ => {
+                        err!(errors; unit; property, "Expected a register like `$1` or `$1..` for returns, but got `{returns}`")
+                    }
+                }
+            }
+            ("caller-saves", None) => convention.caller_saves = true,
+            ("callee-saves", None) => convention.caller_saves = false,
+            ("cleanup", Some("caller")) => convention.caller_cleanup = true,
+            ("cleanup", Some("callee")) => convention.caller_cleanup = false,
+            ("label-prefix", Some(_)) => {
+                let prefix = parse_plain_string(value.unwrap(), unit).extend_into(&mut errors);
+                if prefix.contains('.') {
+                    err!(errors; unit; property, "Label prefix must not contain a dot ('.')");
+                }
+                convention.mangling = Mangling::Prefix(prefix);
+            }
+            (key, Some(text)) => {
+                err!(errors; unit; property, "Unknown calling convention property `{key}: {text}`")
+            }
+            (key, None) => {
+                err!(errors; unit; property, "Unknown calling convention property `{key}`")
+            }
+        }
+    }
+    (convention, errors)
+}
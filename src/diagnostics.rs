@@ -0,0 +1,126 @@
+use super::*;
+use serde_json::{json, Value};
+
+/// One JSON object per line, per error. Lines and columns are 1-based, and the end is exclusive.
+///
+/// ```json
+/// {"end":{"column":9,"line":3},"file":"main.ursl","lint":"unused-label","message":"...","severity":"warning","start":{"column":5,"line":3}}
+/// ```
+///
+/// Errors that aren't about any particular place in the code have `null` for `file`, `start` and `end`, and hard errors have `null` for `lint`.
+pub fn json_lines(errors: &[SourceError]) -> String {
+    let mut out = String::new();
+    for error in errors {
+        let (file, start, end) = match &error.pos {
+            Some(pos) => {
+                let start = pos.range.start_point;
+                let end = pos.range.end_point;
+                (
+                    json!(pos.unit.path()),
+                    json!({ "line": start.row + 1, "column": start.column + 1 }),
+                    json!({ "line": end.row + 1, "column": end.column + 1 }),
+                )
+            }
+            None => (Value::Null, Value::Null, Value::Null),
+        };
+        let line = json!({
+            "file": file,
+            "start": start,
+            "end": end,
+            "severity": error.severity.to_string(),
+            "lint": error.lint.map(|lint| lint.name()),
+            "message": error.message,
+        });
+        out.push_str(&line.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// A SARIF 2.1.0 log with a single run, which is what most CI code scanning integrations want.
+pub fn sarif(errors: &[SourceError]) -> String {
+    let results = errors
+        .iter()
+        .map(|error| {
+            let locations = match &error.pos {
+                Some(pos) => {
+                    let start = pos.range.start_point;
+                    let end = pos.range.end_point;
+                    json!([{
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": pos.unit.path() },
+                            "region": {
+                                "startLine": start.row + 1,
+                                "startColumn": start.column + 1,
+                                "endLine": end.row + 1,
+                                "endColumn": end.column + 1,
+                            },
+                        },
+                    }])
+                }
+                None => json!([]),
+            };
+            let mut result = json!({
+                "level": error.severity.to_string(),
+                "message": { "text": error.message },
+                "locations": locations,
+            });
+            // sarif has no rule id for hard errors, so they just don't get one
+            if let Some(lint) = error.lint {
+                result["ruleId"] = json!(lint.name());
+            }
+            result
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": { "driver": { "name": "ursl", "version": env!("CARGO_PKG_VERSION") } },
+            "results": results,
+        }],
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors() -> Vec<SourceError<'static>> {
+        vec![
+            err!(@ None, "Missing \"quotes\"\n"),
+            SourceError {
+                severity: Severity::Warning,
+                lint: Some(lints::Lint::UnboundedStack),
+                ..err!(@ None, "Can't check")
+            },
+        ]
+    }
+
+    #[test]
+    fn json_lines_escapes_and_omits_positions() {
+        let out = json_lines(&errors());
+        let lines = out
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                json!({ "file": null, "start": null, "end": null, "severity": "error", "lint": null, "message": "Missing \"quotes\"\n" }),
+                json!({ "file": null, "start": null, "end": null, "severity": "warning", "lint": "unbounded-stack", "message": "Can't check" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn sarif_only_has_rule_ids_for_lints() {
+        let log = serde_json::from_str::<Value>(&sarif(&errors())).unwrap();
+        let results = &log["runs"][0]["results"];
+        assert_eq!(results[0].get("ruleId"), None);
+        assert_eq!(results[0]["locations"], json!([]));
+        assert_eq!(results[1]["ruleId"], "unbounded-stack");
+        assert_eq!(results[1]["level"], "warning");
+    }
+}
@@ -0,0 +1,667 @@
+use super::*;
+use num::{BigInt, BigUint, Num, One, ToPrimitive, Zero};
+use ports::Ports;
+use std::{
+    fmt::{self, Display, Formatter},
+    io::BufRead,
+};
+
+/// How a program stopped, if it didn't fail.
+pub struct Outcome {
+    /// Every URCL instruction that was executed counts as one cycle.
+    pub cycles: u64,
+}
+
+/// Something wrong with the URCL itself, or something that went wrong while running it.
+#[derive(Debug)]
+pub struct EmulatorError {
+    /// 1-based, if it's about a particular line.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for EmulatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Operand {
+    /// `$0` is always 0, and writes to it are ignored.
+    Register(usize),
+    Sp,
+    Pc,
+    Imm(BigUint),
+    Port(String),
+}
+
+struct Instruction {
+    line: usize,
+    op: String,
+    operands: Vec<Operand>,
+}
+
+/// A parsed URCL program, ready to run any number of times.
+///
+/// It's laid out like URCL's `RUN ROM`: instructions are separate from memory, and labels on instructions are just
+/// their index. Memory is the `DW` data first, then `MINHEAP` words of heap starting at `M0`, then `MINSTACK` words
+/// of stack, which grows down from the end.
+pub struct Program {
+    headers: Headers,
+    minreg: usize,
+    data: Vec<BigUint>,
+    instructions: Vec<Instruction>,
+}
+
+enum Target {
+    Data(usize),
+    Instruction(usize),
+}
+
+impl Program {
+    pub fn parse(source: &str) -> Result<Self, EmulatorError> {
+        let at = |line| {
+            move |message| EmulatorError {
+                line: Some(line),
+                message,
+            }
+        };
+        // these are the defaults from the URCL spec, for anything not written by the compiler.
+        // BITS is always exact here, even for `BITS >= 8`, since it has to run with some word size
+        let mut headers = Headers {
+            bits: Bits::Exactly(8),
+            minheap: 16u32.into(),
+            minstack: Some(8u32.into()),
+        };
+        let mut minreg = 8;
+
+        // first find every label, since they can be used before they're defined
+        let mut lines = Vec::new();
+        let mut labels = HashMap::new();
+        let mut pending = Vec::new();
+        let mut data_len = 0;
+        let mut instruction_len = 0;
+        for (idx, text) in source.lines().enumerate() {
+            let line = idx + 1;
+            let tokens = tokenize(text).map_err(at(line))?;
+            let Some(first) = tokens.first() else {
+                continue;
+            };
+            if let Some(label) = first.strip_prefix('.') {
+                if tokens.len() > 1 {
+                    return Err(at(line)(format!("Unexpected {} after a label", tokens[1])));
+                }
+                pending.push((line, label.to_owned()));
+                continue;
+            }
+            let mut define = |target: &dyn Fn() -> Target| {
+                for (line, label) in pending.drain(..) {
+                    if labels.insert(label.clone(), target()).is_some() {
+                        return Err(at(line)(format!("Duplicate label .{label}")));
+                    }
+                }
+                Ok(())
+            };
+            let op = first.to_ascii_uppercase();
+            match op.as_str() {
+                "BITS" => headers.bits = Bits::Exactly(header(&tokens[1..]).map_err(at(line))?),
+                "MINHEAP" => headers.minheap = header(&tokens[1..]).map_err(at(line))?,
+                "MINSTACK" => headers.minstack = Some(header(&tokens[1..]).map_err(at(line))?),
+                "MINREG" => minreg = header(&tokens[1..]).map_err(at(line))?,
+                // there's no difference between RAM and ROM here, since nothing can write to instructions anyway
+                "RUN" => (),
+                "DW" => {
+                    define(&|| Target::Data(data_len))?;
+                    data_len += tokens[1..]
+                        .iter()
+                        .map(|token| match token.strip_prefix('"') {
+                            Some(string) => unescape(&string[..string.len() - 1]).map(|s| s.len()),
+                            None if token == "[" || token == "]" => Ok(0),
+                            None => Ok(1),
+                        })
+                        .sum::<Result<usize, _>>()
+                        .map_err(at(line))?;
+                    lines.push((line, tokens));
+                }
+                _ => {
+                    define(&|| Target::Instruction(instruction_len))?;
+                    instruction_len += 1;
+                    lines.push((line, tokens));
+                }
+            }
+        }
+        for (line, label) in pending {
+            if labels
+                .insert(label.clone(), Target::Instruction(instruction_len))
+                .is_some()
+            {
+                return Err(at(line)(format!("Duplicate label .{label}")));
+            }
+        }
+
+        let resolver = Resolver {
+            headers: &headers,
+            minreg,
+            heap: data_len,
+            labels: &labels,
+        };
+        let mut data = Vec::with_capacity(data_len);
+        let mut instructions = Vec::with_capacity(instruction_len);
+        for (line, tokens) in lines {
+            let op = tokens[0].to_ascii_uppercase();
+            if op == "DW" {
+                for token in &tokens[1..] {
+                    match token.strip_prefix('"') {
+                        Some(string) => data.extend(
+                            unescape(&string[..string.len() - 1])
+                                .map_err(at(line))?
+                                .into_iter()
+                                .map(|ch| resolver.wrap(BigUint::from(ch as u32))),
+                        ),
+                        // nested arrays are just flattened
+                        None if token == "[" || token == "]" => (),
+                        None => match resolver.operand(token, instructions.len()) {
+                            Ok(Operand::Imm(value)) => data.push(value),
+                            Ok(_) => {
+                                return Err(at(line)(format!("{token} can't be used as data")))
+                            }
+                            Err(message) => return Err(at(line)(message)),
+                        },
+                    }
+                }
+            } else {
+                let here = instructions.len();
+                let operands = tokens[1..]
+                    .iter()
+                    .map(|token| resolver.operand(token, here))
+                    .collect::<Result<_, _>>()
+                    .map_err(at(line))?;
+                instructions.push(Instruction { line, op, operands });
+            }
+        }
+
+        Ok(Program {
+            headers,
+            minreg,
+            data,
+            instructions,
+        })
+    }
+}
+
+struct Resolver<'r> {
+    headers: &'r Headers,
+    minreg: usize,
+    heap: usize,
+    labels: &'r HashMap<String, Target>,
+}
+
+impl Resolver<'_> {
+    fn wrap(&self, n: BigUint) -> BigUint {
+        n % (BigUint::one() << self.headers.bits.minimum())
+    }
+
+    fn operand(&self, token: &str, here: usize) -> Result<Operand, String> {
+        let upper = token.to_ascii_uppercase();
+        let register = token
+            .strip_prefix('$')
+            .or_else(|| upper.strip_prefix('R'))
+            .and_then(|n| n.parse::<usize>().ok());
+        let heap = token
+            .strip_prefix('#')
+            .or_else(|| upper.strip_prefix('M'))
+            .and_then(|n| n.parse::<BigUint>().ok());
+        let value = if let Some(reg) = register {
+            if reg > self.minreg {
+                return Err(format!(
+                    "{token} is out of range, MINREG is {}",
+                    self.minreg
+                ));
+            }
+            return Ok(Operand::Register(reg));
+        } else if upper == "SP" {
+            return Ok(Operand::Sp);
+        } else if upper == "PC" {
+            return Ok(Operand::Pc);
+        } else if let Some(port) = token.strip_prefix('%') {
+            return Ok(Operand::Port(port.to_owned()));
+        } else if let Some(addr) = heap {
+            self.heap + addr
+        } else if let Some(label) = token.strip_prefix('.') {
+            match self.labels.get(label) {
+                Some(Target::Data(addr) | Target::Instruction(addr)) => BigUint::from(*addr),
+                None => return Err(format!("Undefined label {token}")),
+            }
+        } else if let Some(offset) = token.strip_prefix('~') {
+            let offset = offset
+                .parse::<i64>()
+                .map_err(|err| format!("Invalid relative address {token}: {err}"))?;
+            here.checked_add_signed(offset as isize)
+                .map(BigUint::from)
+                .ok_or_else(|| format!("{token} points before the start of the program"))?
+        } else if let Some(name) = token.strip_prefix('@') {
+            fold::macro_value(name, self.headers, self.minreg)
+                .ok_or_else(|| format!("Unknown macro {token}"))?
+        } else if let Some(ch) = token.strip_prefix('\'') {
+            match unescape(&ch[..ch.len() - 1])?.as_slice() {
+                [ch] => BigUint::from(*ch as u32),
+                _ => return Err(format!("{token} is not a single char")),
+            }
+        } else {
+            let (negative, digits) = match token.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, token),
+            };
+            let lower = digits.to_ascii_lowercase();
+            let (radix, digits) = if let Some(digits) = lower.strip_prefix("0x") {
+                (16, digits)
+            } else if let Some(digits) = lower.strip_prefix("0b") {
+                (2, digits)
+            } else if let Some(digits) = lower.strip_prefix("0o") {
+                (8, digits)
+            } else {
+                (10, lower.as_str())
+            };
+            let n = BigUint::from_str_radix(&digits.replace('_', ""), radix)
+                .map_err(|err| format!("Invalid operand {token}: {err}"))?;
+            if negative {
+                let modulus = BigInt::from(BigUint::one() << self.headers.bits.minimum());
+                let n = BigInt::from(n) % &modulus;
+                ((&modulus - n) % &modulus)
+                    .to_biguint()
+                    .expect("Just made it positive")
+            } else {
+                n
+            }
+        };
+        Ok(Operand::Imm(self.wrap(value)))
+    }
+}
+
+/// `BITS` can also be written as `BITS == 8`, `BITS >= 8` or `BITS <= 8`, and the emulator just takes the number.
+fn header<T: std::str::FromStr>(tokens: &[String]) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    let value = match tokens {
+        [value] => value,
+        [op, value] if matches!(op.as_str(), "==" | ">=" | "<=") => value,
+        _ => return Err(format!("Invalid header: {}", tokens.join(" "))),
+    };
+    value
+        .parse()
+        .map_err(|err| format!("Invalid header value {value}: {err}"))
+}
+
+/// Splits a line into operands, keeping strings and chars in one piece with their quotes, and brackets on their own.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(idx, ch)) = chars.peek() {
+        match ch {
+            _ if ch.is_whitespace() => {
+                chars.next();
+            }
+            '/' if line[idx..].starts_with("//") => break,
+            '[' | ']' => {
+                chars.next();
+                tokens.push(ch.to_string());
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut token = String::from(ch);
+                loop {
+                    match chars.next().map(|(_, ch)| ch) {
+                        Some('\\') => {
+                            token.push('\\');
+                            token.extend(chars.next().map(|(_, ch)| ch));
+                        }
+                        Some(end) if end == ch => break token.push(end),
+                        Some(other) => token.push(other),
+                        None => return Err(format!("Unterminated {ch} in {token}")),
+                    }
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(&(_, ch)) = chars.peek() {
+                    if ch.is_whitespace() || matches!(ch, '[' | ']') {
+                        break;
+                    }
+                    token.push(ch);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Resolves escapes the same way the compiler does, so that `DW "..."` and chars mean the same thing they did in URSL.
+fn unescape(text: &str) -> Result<Vec<char>, String> {
+    let mut result = Vec::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        let next = chars.next();
+        let mut hex = |len| {
+            let digits = chars.by_ref().take(len).collect::<String>();
+            u32::from_str_radix(&digits, 16)
+                .map_err(|err| format!("Invalid escape {digits}: {err}"))
+        };
+        let escape = match next {
+            Some('x') => CharEscape::Hex(hex(2)? as u8),
+            Some('u') => CharEscape::Unicode(hex(4)?),
+            Some('U') => CharEscape::Unicode(hex(8)?),
+            Some(ch) => CharEscape::Char(ch),
+            None => return Err("Escape at the end of a string".to_owned()),
+        };
+        result.push(lower_char_escape(escape).unwrap_or(escape.into()));
+    }
+    Ok(result)
+}
+
+/// Runs a program from its first instruction, with ports on `input` and `output`, until it halts or runs off the end.
+///
+/// Stack overflows, out of bounds memory accesses, division by zero and anything the emulator doesn't know are errors.
+pub fn emulate(
+    program: &Program,
+    input: impl BufRead,
+    output: impl Write,
+    max_cycles: Option<u64>,
+) -> Result<Outcome, EmulatorError> {
+    let mut machine = Machine::new(program, Ports::new(input, output))?;
+    let outcome = machine.run(max_cycles);
+    machine.ports.flush().map_err(|err| EmulatorError {
+        line: None,
+        message: format!("Can't write the output: {err}"),
+    })?;
+    outcome
+}
+
+struct Machine<'p, R, W> {
+    program: &'p Program,
+    registers: Vec<BigUint>,
+    memory: Vec<BigUint>,
+    /// The lowest address `PSH` can write to, which is right after the heap.
+    stack_limit: usize,
+    sp: usize,
+    pc: usize,
+    ports: Ports<R, W>,
+    cycles: u64,
+}
+
+impl<'p, R: BufRead, W: Write> Machine<'p, R, W> {
+    fn new(program: &'p Program, ports: Ports<R, W>) -> Result<Self, EmulatorError> {
+        let headers = &program.headers;
+        let stack_limit = program.data.len() + &headers.minheap;
+        let size = &stack_limit + headers.minstack.clone().unwrap_or_default();
+        let error = |message| EmulatorError {
+            line: None,
+            message,
+        };
+        // a stack pointer of exactly 2^BITS is fine, since it wraps around to 0 and the first PSH goes to @MAX
+        if size > BigUint::one() << headers.bits.minimum() {
+            return Err(error(format!(
+                "The program needs {size} words of memory, but BITS {} can't address that many",
+                headers.bits
+            )));
+        }
+        let (Some(stack_limit), Some(size)) = (stack_limit.to_usize(), size.to_usize()) else {
+            return Err(error(format!(
+                "The program needs {size} words of memory, which is too much to emulate"
+            )));
+        };
+        let mut memory = program.data.clone();
+        memory.resize(size, BigUint::zero());
+        Ok(Machine {
+            program,
+            registers: vec![BigUint::zero(); program.minreg + 1],
+            memory,
+            stack_limit,
+            sp: size,
+            pc: 0,
+            ports,
+            cycles: 0,
+        })
+    }
+
+    fn run(&mut self, max_cycles: Option<u64>) -> Result<Outcome, EmulatorError> {
+        let program = self.program;
+        while let Some(inst) = program.instructions.get(self.pc) {
+            let at = |message| EmulatorError {
+                line: Some(inst.line),
+                message,
+            };
+            if max_cycles.is_some_and(|max| self.cycles >= max) {
+                return Err(at(format!("Gave up after {} cycles", self.cycles)));
+            }
+            self.cycles += 1;
+            let here = self.pc;
+            self.pc += 1;
+            if !self.execute(inst, here).map_err(at)? {
+                break;
+            }
+        }
+        Ok(Outcome {
+            cycles: self.cycles,
+        })
+    }
+
+    fn wrap(&self, n: BigUint) -> BigUint {
+        n % (BigUint::one() << self.program.headers.bits.minimum())
+    }
+
+    fn read(&self, operand: &Operand, here: usize) -> Result<BigUint, String> {
+        Ok(match operand {
+            Operand::Register(reg) => self.registers[*reg].clone(),
+            Operand::Sp => self.wrap(self.sp.into()),
+            Operand::Pc => self.wrap(here.into()),
+            Operand::Imm(value) => value.clone(),
+            Operand::Port(port) => return Err(format!("%{port} can only be used with IN and OUT")),
+        })
+    }
+
+    fn write(&mut self, operand: &Operand, value: BigUint) -> Result<(), String> {
+        match operand {
+            Operand::Register(0) => (),
+            Operand::Register(reg) => self.registers[*reg] = value,
+            Operand::Sp => self.sp = self.usize(&value),
+            Operand::Pc => self.pc = self.usize(&value),
+            Operand::Imm(_) | Operand::Port(_) => {
+                return Err("The destination must be a register".to_owned())
+            }
+        }
+        Ok(())
+    }
+
+    fn usize(&self, value: &BigUint) -> usize {
+        // anything that doesn't fit is out of bounds for memory and past the end of the program anyway
+        value.to_usize().unwrap_or(usize::MAX)
+    }
+
+    fn address(&self, value: &BigUint) -> Result<usize, String> {
+        match value.to_usize() {
+            Some(addr) if addr < self.memory.len() => Ok(addr),
+            _ => Err(format!(
+                "Memory access out of bounds at address {value} (memory is {} words)",
+                self.memory.len()
+            )),
+        }
+    }
+
+    fn push(&mut self, value: BigUint) -> Result<(), String> {
+        if self.sp <= self.stack_limit {
+            return Err(format!(
+                "Stack overflow, MINSTACK {} is not enough",
+                self.memory.len() - self.stack_limit
+            ));
+        }
+        self.sp -= 1;
+        let addr = self.address(&self.sp.into())?;
+        self.memory[addr] = value;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<BigUint, String> {
+        if self.sp >= self.memory.len() {
+            return Err("Stack underflow".to_owned());
+        }
+        self.sp += 1;
+        Ok(self.memory[self.sp - 1].clone())
+    }
+
+    /// Returns whether to keep going.
+    fn execute(&mut self, inst: &Instruction, here: usize) -> Result<bool, String> {
+        let bits = self.program.headers.bits.minimum();
+        let read = |operand| self.read(operand, here);
+        match (inst.op.as_str(), inst.operands.as_slice()) {
+            ("HLT", []) => return Ok(false),
+            ("NOP", []) => (),
+            ("MOV" | "IMM", [dest, a]) => {
+                let value = read(a)?;
+                self.write(dest, value)?;
+            }
+            ("LOD", [dest, a]) => {
+                let addr = self.address(&read(a)?)?;
+                self.write(dest, self.memory[addr].clone())?;
+            }
+            ("LLOD", [dest, a, b]) => {
+                let addr = self.address(&self.wrap(read(a)? + read(b)?))?;
+                self.write(dest, self.memory[addr].clone())?;
+            }
+            ("STR", [a, b]) => {
+                let addr = self.address(&read(a)?)?;
+                self.memory[addr] = read(b)?;
+            }
+            ("LSTR", [a, b, c]) => {
+                let addr = self.address(&self.wrap(read(a)? + read(b)?))?;
+                self.memory[addr] = read(c)?;
+            }
+            ("CPY", [a, b]) => {
+                let (dest, src) = (self.address(&read(a)?)?, self.address(&read(b)?)?);
+                self.memory[dest] = self.memory[src].clone();
+            }
+            ("PSH", [a]) => {
+                let value = read(a)?;
+                self.push(value)?;
+            }
+            ("POP", [dest]) => {
+                let value = self.pop()?;
+                self.write(dest, value)?;
+            }
+            ("CAL", [a]) => {
+                let dest = read(a)?;
+                self.push(self.wrap(self.pc.into()))?;
+                self.pc = self.usize(&dest);
+            }
+            ("RET", []) => {
+                let dest = self.pop()?;
+                self.pc = self.usize(&dest);
+            }
+            ("JMP", [a]) => self.pc = self.usize(&read(a)?),
+            ("IN", [dest, Operand::Port(port)]) => match self.ports.read(port, bits) {
+                Some(Ok(value)) => self.write(dest, value)?,
+                Some(Err(err)) => return Err(format!("Can't read from %{port}: {err}")),
+                None => return Err(format!("Port %{port} is not supported")),
+            },
+            ("OUT", [Operand::Port(port), a]) => {
+                let value = read(a)?;
+                match self.ports.write(port, &value, bits) {
+                    Some(Ok(())) => (),
+                    Some(Err(err)) => return Err(format!("Can't write to %{port}: {err}")),
+                    None => return Err(format!("Port %{port} is not supported")),
+                }
+            }
+            (op, [dest, sources @ ..]) => {
+                let values = sources.iter().map(read).collect::<Result<Vec<_>, _>>()?;
+                if let Some(taken) = fold::condition(op, &values, bits) {
+                    if taken {
+                        self.pc = self.usize(&read(dest)?);
+                    }
+                } else if let Some(value) = fold::evaluate(op, &values, bits) {
+                    self.write(dest, value)?;
+                } else if matches!(op, "DIV" | "MOD" | "SDIV" | "SMOD") && values.len() == 2 {
+                    return Err("Division by zero".to_owned());
+                } else {
+                    return Err(format!(
+                        "Unknown instruction {op} with {} operands",
+                        sources.len() + 1
+                    ));
+                }
+            }
+            (op, []) => return Err(format!("Unknown instruction {op} without operands")),
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::*;
+
+    #[test]
+    fn arithmetic_wraps_at_bits() {
+        let urcl = "BITS 8\nMINREG 2\nIMM R1 250\nADD R1 R1 10\nOUT %NUMB R1\nSUB R2 R0 1\nOUT %TEXT ' '\nOUT %NUMB R2\nHLT\n";
+        assert_eq!(emulate_urcl(urcl).unwrap(), "4 255");
+    }
+
+    #[test]
+    fn calls_and_relative_jumps() {
+        let urcl = "BITS 8\nMINREG 1\nMINSTACK 4\nCAL .twice\nOUT %NUMB R1\nHLT\n.twice\nIMM R1 3\nJMP ~+2\nIMM R1 100\nADD R1 R1 R1\nRET\n";
+        assert_eq!(emulate_urcl(urcl).unwrap(), "6");
+    }
+
+    #[test]
+    fn data_is_before_the_heap() {
+        let urcl = "BITS 8\nMINREG 1\n.nums\nDW [ 7 \"hi\" ]\nLOD R1 .nums\nOUT %NUMB R1\nLLOD R1 .nums 2\nOUT %TEXT R1\nHLT\n";
+        assert_eq!(emulate_urcl(urcl).unwrap(), "7i");
+    }
+
+    #[test]
+    fn errors_have_the_line() {
+        let overflow = "BITS 8\nMINSTACK 1\nPSH 1\nPSH 2\nHLT\n";
+        assert_eq!(
+            emulate_urcl(overflow).unwrap_err(),
+            "line 4: Stack overflow, MINSTACK 1 is not enough"
+        );
+        assert_eq!(
+            emulate_urcl("BITS 8\nMINREG 1\nDIV R1 1 0\n").unwrap_err(),
+            "line 3: Division by zero"
+        );
+        assert!(emulate_urcl("BITS 8\nMINREG 1\nIMM R2 1\n")
+            .unwrap_err()
+            .starts_with("line 3: R2 is out of range"));
+        assert_eq!(
+            emulate_urcl("BITS 8\nJMP .loop\n.loop\nJMP .loop\n").unwrap_err(),
+            "line 4: Gave up after 10000000 cycles"
+        );
+    }
+
+    #[test]
+    fn compiled_output_runs() {
+        let source = format!(
+            "{HEADERS}
+            func $main {{ const 6 const 7 mult out %NUMB }}"
+        );
+        assert_eq!(run_source(&source), "42");
+    }
+
+    #[test]
+    fn example_calls_fib_through_hexagn_icall() {
+        // fib(20) is 6765, but the example is `bits 8`
+        assert_eq!(
+            run_source(include_str!("../example.ursl")),
+            (6765 % 256).to_string()
+        );
+    }
+}
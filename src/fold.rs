@@ -0,0 +1,212 @@
+use super::*;
+use num::{BigInt, BigUint, One, Signed, Zero};
+
+/// Evaluates a URCL instruction at compile time, for inputs that are all known numbers.
+///
+/// This only knows about the pure arithmetic instructions used by the prelude. Anything that touches memory or ports,
+/// or would be undefined (like division by zero), returns `None`, and should just be emitted as usual.
+pub fn evaluate(op: &str, inputs: &[BigUint], bits: u64) -> Option<BigUint> {
+    let modulus = BigUint::one() << bits;
+    let max = &modulus - 1u32;
+    let wrap = |n: BigUint| n % &modulus;
+    let signed = |n: &BigUint| to_signed(n, bits);
+    let unsigned = |n: BigInt| {
+        let n = n % BigInt::from(modulus.clone());
+        let n = if n.is_negative() {
+            n + BigInt::from(modulus.clone())
+        } else {
+            n
+        };
+        n.to_biguint().expect("Just made it positive")
+    };
+    let set = |cond: bool| if cond { max.clone() } else { BigUint::zero() };
+    // shifting by more than the word size is perfectly valid, it just shifts everything out
+    let shift = |n: &BigUint| u64::try_from(n).unwrap_or(u64::MAX).min(bits);
+
+    Some(match (op.to_ascii_uppercase().as_str(), inputs) {
+        ("SETNZ", [a]) => set(!a.is_zero()),
+        ("NOT", [a]) => &max ^ a,
+        ("INC", [a]) => wrap(a + 1u32),
+        ("DEC", [a]) => wrap(a + &max),
+        ("NEG", [a]) => wrap(&modulus - a),
+        ("RSH", [a]) => a >> 1u32,
+        ("SRS", [a]) => unsigned(signed(a) >> 1u32),
+        ("LSH", [a]) => wrap(a << 1u32),
+
+        ("XOR", [a, b]) => a ^ b,
+        ("XNOR", [a, b]) => &max ^ (a ^ b),
+        ("AND", [a, b]) => a & b,
+        ("NAND", [a, b]) => &max ^ (a & b),
+        ("OR", [a, b]) => a | b,
+        ("NOR", [a, b]) => &max ^ (a | b),
+        ("ADD", [a, b]) => wrap(a + b),
+        ("SUB", [a, b]) => wrap(a + &modulus - b),
+        ("MLT", [a, b]) => wrap(a * b),
+        ("DIV", [_, b]) | ("MOD", [_, b]) | ("SDIV", [_, b]) | ("SMOD", [_, b]) if b.is_zero() => {
+            return None
+        }
+        ("DIV", [a, b]) => a / b,
+        ("MOD", [a, b]) => a % b,
+        ("SDIV", [a, b]) => unsigned(signed(a) / signed(b)),
+        ("SMOD", [a, b]) => unsigned(signed(a) % signed(b)),
+        ("BSR", [a, b]) => a >> shift(b),
+        ("BSS", [a, b]) => unsigned(signed(a) >> shift(b)),
+        ("BSL", [a, b]) => wrap(a << shift(b)),
+
+        ("SETC", [a, b]) => set(a + b > max),
+        ("SETE", [a, b]) => set(a == b),
+        ("SETNE", [a, b]) => set(a != b),
+        ("SETG", [a, b]) => set(a > b),
+        ("SETGE", [a, b]) => set(a >= b),
+        ("SETL", [a, b]) => set(a < b),
+        ("SETLE", [a, b]) => set(a <= b),
+        ("SSETG", [a, b]) => set(signed(a) > signed(b)),
+        ("SSETGE", [a, b]) => set(signed(a) >= signed(b)),
+        ("SSETL", [a, b]) => set(signed(a) < signed(b)),
+        ("SSETLE", [a, b]) => set(signed(a) <= signed(b)),
+        _ => return None,
+    })
+}
+
+/// Evaluates the condition of a URCL branch instruction, for inputs that are all known numbers.
+///
+/// The destination is not part of `inputs`. Returns `None` for anything that isn't a conditional branch.
+pub fn condition(op: &str, inputs: &[BigUint], bits: u64) -> Option<bool> {
+    let max = (BigUint::one() << bits) - 1u32;
+    let signed = |n: &BigUint| to_signed(n, bits);
+    let negative = |n: &BigUint| bits != 0 && n.bit(bits - 1);
+
+    Some(match (op.to_ascii_uppercase().as_str(), inputs) {
+        ("BRZ", [a]) => a.is_zero(),
+        ("BNZ", [a]) => !a.is_zero(),
+        ("BRP", [a]) => !negative(a),
+        ("BRN", [a]) => negative(a),
+        ("BEV", [a]) => !a.bit(0),
+        ("BOD", [a]) => a.bit(0),
+
+        ("BRE", [a, b]) => a == b,
+        ("BNE", [a, b]) => a != b,
+        ("BRG", [a, b]) => a > b,
+        ("BGE", [a, b]) => a >= b,
+        ("BRL", [a, b]) => a < b,
+        ("BLE", [a, b]) => a <= b,
+        ("SBRG", [a, b]) => signed(a) > signed(b),
+        ("SBGE", [a, b]) => signed(a) >= signed(b),
+        ("SBRL", [a, b]) => signed(a) < signed(b),
+        ("SBLE", [a, b]) => signed(a) <= signed(b),
+        ("BRC", [a, b]) => a + b > max,
+        ("BNC", [a, b]) => a + b <= max,
+        _ => return None,
+    })
+}
+
+/// The value of a URCL macro like `@MAX`. `@MINREG` is only known after emitting, so it's given separately.
+///
+/// Anything that depends on the word size is `None` unless the bits header is exact.
+pub fn macro_value(name: &str, headers: &Headers, minreg: usize) -> Option<BigUint> {
+    let bits = headers.bits.exact();
+    let one = || BigUint::one();
+    Some(match name.to_ascii_uppercase().as_str() {
+        "BITS" => bits?.into(),
+        "MAX" => (one() << bits?) - 1u32,
+        "SMAX" => (one() << bits?.saturating_sub(1)) - 1u32,
+        "MSB" => one() << bits?.saturating_sub(1),
+        "SMSB" => one() << bits?.saturating_sub(2),
+        "UHALF" => ((one() << (bits? - bits? / 2)) - 1u32) << (bits? / 2),
+        "LHALF" => (one() << (bits? / 2)) - 1u32,
+        "MINHEAP" | "HEAP" => headers.minheap.clone(),
+        "MINSTACK" => headers.minstack.clone()?,
+        "MINREG" => minreg.into(),
+        _ => return None,
+    })
+}
+
+fn to_signed(n: &BigUint, bits: u64) -> BigInt {
+    if bits != 0 && n.bit(bits - 1) {
+        BigInt::from(n.clone()) - BigInt::from(BigUint::one() << bits)
+    } else {
+        BigInt::from(n.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn eval(op: &str, inputs: &[u32], bits: u64) -> Option<u32> {
+        let inputs = inputs.iter().map(|&n| n.into()).collect::<Vec<_>>();
+        evaluate(op, &inputs, bits).map(|n| u32::try_from(&n).unwrap())
+    }
+
+    #[test]
+    fn arithmetic_wraps_around() {
+        assert_eq!(eval("ADD", &[250, 10], 8), Some(4));
+        assert_eq!(eval("SUB", &[3, 5], 8), Some(254));
+        assert_eq!(eval("MLT", &[16, 17], 8), Some(16));
+        assert_eq!(eval("INC", &[255], 8), Some(0));
+        assert_eq!(eval("DEC", &[0], 8), Some(255));
+        assert_eq!(eval("NEG", &[0], 8), Some(0));
+        assert_eq!(eval("NEG", &[1], 16), Some(0xffff));
+        assert_eq!(eval("LSH", &[0x80], 8), Some(0));
+        assert_eq!(eval("BSL", &[1, 200], 8), Some(0));
+    }
+
+    #[test]
+    fn signed_ops_use_the_word_size() {
+        assert_eq!(eval("SRS", &[0x80], 8), Some(0xc0));
+        assert_eq!(eval("BSS", &[0xf0, 2], 8), Some(0xfc));
+        assert_eq!(eval("SDIV", &[0xfa, 2], 8), Some(0xfd));
+        assert_eq!(eval("SMOD", &[0xf9, 2], 8), Some(0xff));
+        assert_eq!(eval("SSETL", &[0xff, 0], 8), Some(0xff));
+        assert_eq!(eval("SETL", &[0xff, 0], 8), Some(0));
+        assert_eq!(eval("SETC", &[200, 56], 8), Some(0xff));
+        assert_eq!(eval("SETC", &[200, 55], 8), Some(0));
+    }
+
+    #[test]
+    fn undefined_is_left_alone() {
+        assert_eq!(eval("DIV", &[1, 0], 8), None);
+        assert_eq!(eval("SMOD", &[1, 0], 8), None);
+        assert_eq!(eval("LOD", &[1], 8), None);
+        assert_eq!(eval("ADD", &[1], 8), None);
+    }
+
+    #[test]
+    fn conditions() {
+        let cond = |op, inputs: &[u32]| {
+            let inputs = inputs.iter().map(|&n| n.into()).collect::<Vec<_>>();
+            condition(op, &inputs, 8)
+        };
+        assert_eq!(cond("BRN", &[0x80]), Some(true));
+        assert_eq!(cond("BRP", &[0x7f]), Some(true));
+        assert_eq!(cond("SBRL", &[0x80, 0x7f]), Some(true));
+        assert_eq!(cond("BRL", &[0x80, 0x7f]), Some(false));
+        assert_eq!(cond("BRC", &[0x80, 0x80]), Some(true));
+        assert_eq!(cond("ADD", &[1, 2]), None);
+    }
+
+    #[test]
+    fn folded_code_matches_the_emulator() {
+        let source = format!(
+            "{HEADERS}
+            func $main {{
+                const 250 const 10 add out %NUMB
+                const 3 const 5 sub out %NUMB
+            }}"
+        );
+        let urcl = compile_source(&source).unwrap();
+        assert!(urcl.contains("OUT %NUMB 4\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "4254");
+    }
+
+    #[test]
+    fn only_exact_word_sizes_are_folded() {
+        let urcl = compile_source(
+            "bits >= 8\nminheap 16\nfunc $main { const 250 const 10 add out %NUMB }",
+        )
+        .unwrap();
+        assert!(!urcl.contains("OUT %NUMB 4\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "4");
+    }
+}
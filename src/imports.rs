@@ -0,0 +1,299 @@
+use super::*;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A source file read from disk, in the order it should be compiled in.
+pub struct SourceFile {
+    pub path: String,
+    pub source: String,
+    pub tree: Tree,
+    errors: Vec<(tree_sitter::Range, String)>,
+}
+
+impl SourceFile {
+    /// Errors that occurred while resolving the imports of this file. The unit must be the one parsed from this file.
+    pub fn import_errors<'a>(&self, unit: &'a CompilationUnit<'a>) -> Vec<SourceError<'a>> {
+        self.errors
+            .iter()
+            .map(|(range, message)| {
+                err!(@ Some(Position {
+                    unit,
+                    range: *range,
+                }), "{message}")
+            })
+            .collect()
+    }
+}
+
+/// Reads the given files and everything they import, recursively.
+///
+/// The result is ordered such that every file comes after all the files it imports, so it can be passed to [`compile`] as is.
+/// Each file is only included once, no matter how many times it is imported. The roots are loaded in order, so the main file should be the last one.
+///
+/// Failing to read a root is an IO error, but failing to resolve an import is reported in [`SourceFile::import_errors`] of the importing file.
+pub fn load_sources(roots: &[impl AsRef<Path>]) -> io::Result<Vec<SourceFile>> {
+    let mut loader = Loader::new();
+    for root in roots {
+        let root = root.as_ref();
+        let canonical = fs::canonicalize(root)?;
+        if loader.loaded.contains(&canonical) {
+            continue;
+        }
+        let source = fs::read_to_string(root)?;
+        loader.load(root.to_string_lossy().into_owned(), canonical, source);
+    }
+    Ok(loader.files)
+}
+
+/// Like [`load_sources`] with a single root, but its source is given instead of read from disk. Its imports are still read from disk.
+///
+/// This is what an editor wants, since the file it's editing may have unsaved changes, or not even exist on disk yet.
+pub fn load_sources_from(root: impl AsRef<Path>, source: String) -> Vec<SourceFile> {
+    let mut loader = Loader::new();
+    let root = root.as_ref();
+    let canonical = fs::canonicalize(root).unwrap_or_else(|_| root.to_owned());
+    loader.load(root.to_string_lossy().into_owned(), canonical, source);
+    loader.files
+}
+
+struct Loader {
+    parser: tree_sitter::Parser,
+    files: Vec<SourceFile>,
+    // only contains files that are completely loaded, so that cycles can be told apart from diamonds
+    loaded: HashSet<PathBuf>,
+    in_progress: Vec<(PathBuf, String)>,
+}
+
+impl Loader {
+    fn new() -> Self {
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(tree_sitter_ursl::language())
+            .expect("Failed to set language. For sure unreachable.");
+        Loader {
+            parser,
+            files: Vec::new(),
+            loaded: HashSet::new(),
+            in_progress: Vec::new(),
+        }
+    }
+
+    fn load(&mut self, path: String, canonical: PathBuf, source: String) {
+        let tree = parse_tree(&mut self.parser, &path, &source);
+        self.in_progress.push((canonical.clone(), path.clone()));
+
+        let mut errors = Vec::new();
+        let mut imported_here = HashMap::<PathBuf, tree_sitter::Range>::new();
+        let dir = Path::new(&path)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_owned();
+        for node in tree
+            .root_node()
+            .children_by_field_name("imports", &mut tree.walk())
+        {
+            let Some(string) = node.child_by_field_name("path") else {
+                // this is also a syntax error, but there's nothing to import either way
+                errors.push((node.range(), "Expected a path to import".to_owned()));
+                continue;
+            };
+            if string
+                .children_by_field_name("content", &mut tree.walk())
+                .any(|segment| segment.kind() != "string_segment")
+            {
+                errors.push((
+                    node.range(),
+                    "Escape sequences are not supported in import paths".to_owned(),
+                ));
+                continue;
+            }
+            let text = &source[string.byte_range()];
+            let import = dir.join(&text[1..text.len() - 1]);
+            let import_path = import.to_string_lossy().into_owned();
+            let canonical = match fs::canonicalize(&import) {
+                Ok(canonical) => canonical,
+                Err(err) => {
+                    errors.push((node.range(), format!("Can't import {import_path}: {err}")));
+                    continue;
+                }
+            };
+
+            if let Some(previous) = imported_here.insert(canonical.clone(), node.range()) {
+                errors.push((
+                    node.range(),
+                    format!(
+                        "Duplicate import of {import_path}, previously imported at {path}:{}:{}",
+                        previous.start_point.row + 1,
+                        previous.start_point.column + 1
+                    ),
+                ));
+                continue;
+            }
+            if self.loaded.contains(&canonical) {
+                continue;
+            }
+            if let Some(idx) = self.in_progress.iter().position(|(p, _)| *p == canonical) {
+                let mut cycle = self.in_progress[idx..]
+                    .iter()
+                    .map(|(_, path)| path.as_str())
+                    .collect::<Vec<_>>();
+                cycle.push(&import_path);
+                errors.push((
+                    node.range(),
+                    format!("Import cycle: {}", cycle.join(" -> ")),
+                ));
+                continue;
+            }
+            match fs::read_to_string(&import) {
+                Ok(source) => self.load(import_path, canonical, source),
+                Err(err) => {
+                    errors.push((node.range(), format!("Can't import {import_path}: {err}")))
+                }
+            }
+        }
+
+        self.in_progress.pop();
+        self.loaded.insert(canonical);
+        self.files.push(SourceFile {
+            path,
+            source,
+            tree,
+            errors,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// A fresh directory for the files of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ursl-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn messages(file: &SourceFile) -> Vec<&str> {
+        file.errors
+            .iter()
+            .map(|(_, message)| message.as_str())
+            .collect()
+    }
+
+    /// Compiles everything that was loaded, with the last file as the main file.
+    fn compile_files(files: &[SourceFile]) -> Result<String, Vec<String>> {
+        let prelude = CompilationUnit::prelude();
+        let sources = files
+            .iter()
+            .map(CompilationUnit::from_file)
+            .collect::<Vec<_>>();
+        let mut units = vec![&prelude];
+        units.extend(sources.iter());
+        compile_to_string(&Args::default(), &units).map_err(|errors| {
+            errors
+                .into_iter()
+                .filter(SourceError::is_error)
+                .map(|error| error.message)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn imports_are_relative_to_the_importing_file() {
+        let dir = temp_dir("relative");
+        fs::create_dir(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib/util.ursl"),
+            "import \"inner.ursl\"\nfunc $one 0 -> 1 { const 1 ret }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib/inner.ursl"),
+            "func $two 0 -> 1 { const 2 ret }",
+        )
+        .unwrap();
+        let files = load_sources_from(
+            dir.join("main.ursl"),
+            format!(
+                "{HEADERS}import \"lib/util.ursl\"
+                func $main {{ call $one call $two add out %numb }}"
+            ),
+        );
+        let paths = files
+            .iter()
+            .map(|file| PathBuf::from(&file.path))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                dir.join("lib/inner.ursl"),
+                dir.join("lib/util.ursl"),
+                dir.join("main.ursl")
+            ]
+        );
+        assert!(files.iter().all(|file| file.errors.is_empty()));
+        let urcl = compile_files(&files).unwrap();
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "3");
+    }
+
+    #[test]
+    fn import_cycles_are_errors() {
+        let dir = temp_dir("cycle");
+        fs::write(dir.join("a.ursl"), "import \"b.ursl\"").unwrap();
+        fs::write(dir.join("b.ursl"), "import \"a.ursl\"").unwrap();
+        let files = load_sources_from(
+            dir.join("main.ursl"),
+            format!("{HEADERS}import \"a.ursl\"\nfunc $main {{ }}"),
+        );
+        let b = files
+            .iter()
+            .find(|file| file.path.ends_with("b.ursl"))
+            .unwrap();
+        let a = dir.join("a.ursl").to_string_lossy().into_owned();
+        let b_path = dir.join("b.ursl").to_string_lossy().into_owned();
+        assert_eq!(
+            messages(b),
+            [format!("Import cycle: {a} -> {b_path} -> {a}")]
+        );
+    }
+
+    #[test]
+    fn definitions_collide_across_files() {
+        let dir = temp_dir("duplicate");
+        fs::write(dir.join("lib.ursl"), "func $f 0 -> 0 { ret }").unwrap();
+        let files = load_sources_from(
+            dir.join("main.ursl"),
+            format!(
+                "{HEADERS}import \"lib.ursl\"
+                func $f 0 -> 0 {{ ret }}
+                func $main {{ call $f }}"
+            ),
+        );
+        let errors = compile_files(&files).unwrap_err();
+        assert!(
+            errors
+                .iter()
+                .any(|error| error.starts_with("Duplicate func `$f`")),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn import_paths_cant_have_escapes() {
+        let dir = temp_dir("escape");
+        let files = load_sources_from(
+            dir.join("main.ursl"),
+            format!("{HEADERS}import \"lib\\n.ursl\"\nfunc $main {{ }}"),
+        );
+        assert_eq!(
+            messages(&files[0]),
+            ["Escape sequences are not supported in import paths"]
+        );
+    }
+}
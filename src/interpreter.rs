@@ -0,0 +1,676 @@
+use super::*;
+use num::{BigUint, One, ToPrimitive, Zero};
+use ports::Ports;
+use std::io::BufRead;
+use ursl::{Instruction, InstructionEntry, LocalLabel};
+
+/// How a program stopped, if it didn't fail.
+pub struct Outcome {
+    /// Every URSL instruction counts as one step, and so does every URCL instruction in the body of an `inst`.
+    pub steps: u64,
+}
+
+/// Runs a compiled program from `$main`, without going through URCL, with ports on `input` and `output`.
+///
+/// Memory is laid out like the emitted program would be: the data first, then `MINHEAP` words of heap, then
+/// `MINSTACK` words of callstack for arguments and locals. Function pointers are not addresses in that memory,
+/// just numbers that `icall` knows how to call. Errors are positioned at the instruction that failed.
+pub fn run<'a>(
+    result: &CompileResult<'a>,
+    input: impl BufRead,
+    output: impl Write,
+    max_steps: Option<u64>,
+) -> Result<Outcome, SourceError<'a>> {
+    let main = result
+        .functions
+        .get("$main")
+        .ok_or_else(|| err!(@ None, "There is no $main to run"))?;
+    let too_big = |name| err!(@ None, "The `{name}` header is too big to run");
+    let minheap = result
+        .headers
+        .minheap
+        .to_usize()
+        .ok_or_else(|| too_big("minheap"))?;
+    let minstack = match &result.headers.minstack {
+        Some(minstack) => Some(minstack.to_usize().ok_or_else(|| too_big("minstack"))?),
+        None => None,
+    };
+    let mut machine = Machine::new(
+        result,
+        minheap,
+        minstack,
+        Ports::new(input, output),
+        max_steps,
+    );
+    let outcome = machine.run(main);
+    machine
+        .ports
+        .flush()
+        .map_err(|err| err!(@ None, "Can't write the output: {err}"))?;
+    outcome
+}
+
+struct Machine<'r, 'a, R, W> {
+    result: &'r CompileResult<'a>,
+    bits: u64,
+    memory: Vec<BigUint>,
+    /// The address of `#0`.
+    heap: usize,
+    /// The lowest address the callstack is allowed to grow down to, which is the end of the heap.
+    stack_limit: usize,
+    sp: usize,
+    data: HashMap<&'a str, usize>,
+    /// Function pointers are indices into this.
+    funcs: Vec<&'a str>,
+    labels: HashMap<&'a str, HashMap<LocalLabel<'a>, usize>>,
+    ports: Ports<R, W>,
+    steps: u64,
+    max_steps: Option<u64>,
+}
+
+struct Frame<'r, 'a> {
+    func: &'r Function<'a>,
+    instructions: &'r [InstructionEntry<'a>],
+    pc: usize,
+    /// The address of local 0.
+    base: usize,
+    size: usize,
+    stack: Vec<BigUint>,
+}
+
+macro_rules! fail {
+    ($entry:expr, $($t:tt)*) => {
+        return Err(err!(@ Some($entry.node.pos($entry.unit)), $($t)*))
+    };
+}
+
+impl<'r, 'a, R: BufRead, W: Write> Machine<'r, 'a, R, W> {
+    fn new(
+        result: &'r CompileResult<'a>,
+        minheap: usize,
+        minstack: Option<usize>,
+        ports: Ports<R, W>,
+        max_steps: Option<u64>,
+    ) -> Self {
+        let mut machine = Machine {
+            result,
+            // the program has to work with the smallest word size it allows, so that's what it gets
+            bits: result.headers.bits.minimum(),
+            memory: Vec::new(),
+            heap: 0,
+            stack_limit: 0,
+            sp: 0,
+            data: HashMap::new(),
+            funcs: result.functions.keys().copied().collect(),
+            labels: HashMap::new(),
+            ports,
+            steps: 0,
+            max_steps,
+        };
+        // labels first, since data can point to later data
+        let mut address = 0;
+        for (label, val) in &result.defs {
+            machine.data.insert(label, address);
+            address += data_len(val);
+        }
+        for (_, val) in &result.defs {
+            let words = machine.data_words(val);
+            machine.memory.extend(words);
+        }
+        machine.heap = machine.memory.len();
+        machine.stack_limit = machine.heap + minheap;
+        // without MINSTACK, the callstack gets whatever is left of the address space, within reason
+        let minstack = minstack.unwrap_or_else(|| {
+            let space = (BigUint::one() << machine.bits)
+                .to_usize()
+                .unwrap_or(usize::MAX);
+            space.saturating_sub(machine.stack_limit).min(1 << 16)
+        });
+        machine.sp = machine.stack_limit + minstack;
+        machine.memory.resize(machine.sp, BigUint::zero());
+
+        for func in result.functions.values() {
+            if let FunctionBody::Ursl { instructions, .. } = &func.body {
+                let labels = instructions
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, entry)| match entry.instruction {
+                        Instruction::Label(label) => Some((label, idx)),
+                        _ => None,
+                    })
+                    .collect();
+                machine.labels.insert(func.name, labels);
+            }
+        }
+        machine
+    }
+
+    fn wrap(&self, n: BigUint) -> BigUint {
+        n % (BigUint::one() << self.bits)
+    }
+
+    fn literal(&self, lit: &Literal<'a>) -> Option<BigUint> {
+        let value = match lit {
+            Literal::Char(ch) => BigUint::from(*ch as u32),
+            Literal::CharEscape(esc) => {
+                BigUint::from(lower_char_escape(*esc).unwrap_or((*esc).into()) as u32)
+            }
+            Literal::Macro(name) => {
+                let headers = Headers {
+                    bits: Bits::Exactly(self.bits),
+                    ..self.result.headers.clone()
+                };
+                fold::macro_value(name, &headers, 0)?
+            }
+            Literal::Num(n) => n.clone(),
+            Literal::Mem(idx) => BigUint::from(self.heap) + idx,
+            Literal::Label(label) => BigUint::from(*self.data.get(label)?),
+            Literal::Func(name) => BigUint::from(self.funcs.iter().position(|f| f == name)?),
+        };
+        Some(self.wrap(value))
+    }
+
+    fn data_words(&self, val: &DataLiteral<'a>) -> Vec<BigUint> {
+        match val {
+            // anything unknown has already been reported by compile
+            DataLiteral::Literal(lit) => vec![self.literal(lit).unwrap_or_default()],
+            DataLiteral::Array(items) => items
+                .iter()
+                .flat_map(|(_, item)| self.data_words(item))
+                .collect(),
+            DataLiteral::String(string) => string
+                .chars()
+                .into_iter()
+                .map(|ch| self.wrap((ch as u32).into()))
+                .collect(),
+        }
+    }
+
+    fn step(&mut self, entry: &InstructionEntry<'a>) -> Result<(), SourceError<'a>> {
+        self.steps += 1;
+        if let Some(max) = self.max_steps {
+            if self.steps > max {
+                fail!(entry, "Gave up after {max} steps");
+            }
+        }
+        Ok(())
+    }
+
+    fn enter(
+        &mut self,
+        entry: &InstructionEntry<'a>,
+        func: &'r Function<'a>,
+        args: Vec<BigUint>,
+    ) -> Result<Frame<'r, 'a>, SourceError<'a>> {
+        let FunctionBody::Ursl {
+            locals,
+            ref instructions,
+            ..
+        } = func.body
+        else {
+            unreachable!("Only URSL functions get a frame");
+        };
+        let size = args.len() + locals;
+        if self.sp < self.stack_limit + size {
+            fail!(entry, "Stack overflow when calling {}", func.name);
+        }
+        self.sp -= size;
+        let base = self.sp;
+        for (idx, value) in args
+            .into_iter()
+            .chain(std::iter::repeat_n(BigUint::zero(), locals))
+            .enumerate()
+        {
+            self.memory[base + idx] = value;
+        }
+        Ok(Frame {
+            func,
+            instructions,
+            pc: 0,
+            base,
+            size,
+            stack: Vec::new(),
+        })
+    }
+
+    fn run(&mut self, main: &'r Function<'a>) -> Result<Outcome, SourceError<'a>> {
+        let Some(first) = (match &main.body {
+            FunctionBody::Ursl { instructions, .. } => instructions.first(),
+            _ => None,
+        }) else {
+            return Err(
+                err!(@ Some(main.pos.clone()), "$main must be a func with a body to run it"),
+            );
+        };
+        let mut frames = vec![self.enter(first, main, Vec::new())?];
+
+        macro_rules! pop {
+            ($frame:expr, $entry:expr, $count:expr) => {{
+                let count: usize = $count;
+                match $frame.stack.len().checked_sub(count) {
+                    Some(len) => $frame.stack.split_off(len),
+                    None => fail!($entry, "Stack underflow"),
+                }
+            }};
+        }
+
+        loop {
+            let frame = frames
+                .last_mut()
+                .expect("Returning from $main stops the loop");
+            let instructions = frame.instructions;
+            let Some(entry) = instructions.get(frame.pc) else {
+                unreachable!("Parsing makes sure that functions end with ret, jump or halt");
+            };
+            frame.pc += 1;
+            self.step(entry)?;
+            match entry.instruction {
+                Instruction::Height(_) | Instruction::Label(_) => (),
+                Instruction::Perm(ref perm) => {
+                    let inputs = pop!(frame, entry, perm.input);
+                    frame
+                        .stack
+                        .extend(perm.output.iter().map(|&idx| inputs[idx].clone()));
+                }
+                Instruction::Const(ref lit) => match self.literal(lit) {
+                    Some(value) => frame.stack.push(value),
+                    None => fail!(entry, "Can't evaluate {lit}"),
+                },
+                Instruction::In(port) => match self.ports.read(port, self.bits) {
+                    Some(Ok(value)) => frame.stack.push(value),
+                    Some(Err(err)) => fail!(entry, "Can't read from %{port}: {err}"),
+                    None => fail!(entry, "Port %{port} is not supported"),
+                },
+                Instruction::Out(port) => {
+                    let value = pop!(frame, entry, 1).remove(0);
+                    match self.ports.write(port, &value, self.bits) {
+                        Some(Ok(())) => (),
+                        Some(Err(err)) => fail!(entry, "Can't write to %{port}: {err}"),
+                        None => fail!(entry, "Port %{port} is not supported"),
+                    }
+                }
+                Instruction::Jump(label) => frame.pc = self.labels[frame.func.name][&label],
+                Instruction::Branch(opcode, label) => {
+                    let func = &self.result.functions[opcode];
+                    let FunctionBody::Urcl {
+                        branch: Some(ref branch),
+                        ..
+                    } = func.body
+                    else {
+                        fail!(entry, "{opcode} has no branching variant");
+                    };
+                    let inputs = pop!(frame, entry, func.stack.input);
+                    let (_, taken) = self.run_urcl(
+                        entry,
+                        &branch.instructions,
+                        branch.input.registers(),
+                        inputs,
+                        &[],
+                    )?;
+                    if taken {
+                        frame.pc = self.labels[frame.func.name][&label];
+                    }
+                }
+                Instruction::Halt => return Ok(Outcome { steps: self.steps }),
+                Instruction::Call(name) => {
+                    let func = &self.result.functions[name];
+                    let args = pop!(frame, entry, func.stack.input);
+                    if let Some(new_frame) = self.call(entry, func, args, frame)? {
+                        frames.push(new_frame);
+                    }
+                }
+                Instruction::IndirectCall(convention, stack) => {
+                    let mut args = pop!(frame, entry, stack.input + 1);
+                    let ptr = args.remove(0);
+                    if convention != CallingConvention::URSL {
+                        fail!(
+                            entry,
+                            "Can't interpret calls with the {convention} calling convention"
+                        );
+                    }
+                    let func = self.func_pointer(entry, &ptr, stack)?;
+                    if let Some(new_frame) = self.call(entry, func, args, frame)? {
+                        frames.push(new_frame);
+                    }
+                }
+                Instruction::TailCall(_) | Instruction::TailIndirectCall(_) => {
+                    let (func, args) = match entry.instruction {
+                        Instruction::TailCall(name) => {
+                            let func = &self.result.functions[name];
+                            (func, pop!(frame, entry, func.stack.input))
+                        }
+                        Instruction::TailIndirectCall(stack) => {
+                            let mut args = pop!(frame, entry, stack.input + 1);
+                            let ptr = args.remove(0);
+                            (self.func_pointer(entry, &ptr, stack)?, args)
+                        }
+                        _ => unreachable!(),
+                    };
+                    if !matches!(func.body, FunctionBody::Ursl { .. }) {
+                        fail!(
+                            entry,
+                            "Can't tail call {}, since it isn't a func",
+                            func.name
+                        );
+                    }
+                    // the frame is gone before the callee gets its own, so it doesn't grow the callstack
+                    let frame = frames.pop().expect("There is a frame to tail call from");
+                    self.sp += frame.size;
+                    frames.push(self.enter(entry, func, args)?);
+                }
+                Instruction::Ret => {
+                    let mut frame = frames.pop().expect("There is a frame to return from");
+                    let outputs = pop!(frame, entry, frame.func.stack.output);
+                    self.sp += frame.size;
+                    match frames.last_mut() {
+                        Some(caller) => caller.stack.extend(outputs),
+                        None => return Ok(Outcome { steps: self.steps }),
+                    }
+                }
+                Instruction::Ref(idx) => frame.stack.push(self.wrap((frame.base + idx).into())),
+                Instruction::Get(idx) => frame.stack.push(self.memory[frame.base + idx].clone()),
+                Instruction::Set(idx) => {
+                    let value = pop!(frame, entry, 1).remove(0);
+                    self.memory[frame.base + idx] = value;
+                }
+                Instruction::Fused(_) => {
+                    unreachable!("Fused instructions are only created while emitting")
+                }
+            }
+        }
+    }
+
+    /// Calls anything that isn't a URSL func right away. For URSL funcs, the new frame is returned instead.
+    fn call(
+        &mut self,
+        entry: &InstructionEntry<'a>,
+        func: &'r Function<'a>,
+        args: Vec<BigUint>,
+        frame: &mut Frame<'r, 'a>,
+    ) -> Result<Option<Frame<'r, 'a>>, SourceError<'a>> {
+        match &func.body {
+            FunctionBody::Ursl { .. } => return self.enter(entry, func, args).map(Some),
+            FunctionBody::Urcl {
+                overloads, branch, ..
+            } => {
+                // every overload does the same thing, they're only different for register allocation
+                let Some(body) = overloads.first() else {
+                    fail!(entry, "{} only has a branching variant", func.name);
+                };
+                let (outputs, _) = self.run_urcl(
+                    entry,
+                    &body.instructions,
+                    body.input.registers(),
+                    args,
+                    body.output.registers(),
+                )?;
+                debug_assert!(branch.is_some() || outputs.len() == func.stack.output);
+                frame.stack.extend(outputs);
+            }
+            FunctionBody::Permutation(perm) => {
+                frame
+                    .stack
+                    .extend(perm.output.iter().map(|&idx| args[idx].clone()));
+            }
+            FunctionBody::Extern(convention, label) => {
+                fail!(entry, "Can't call extern \"{convention}\" func {} (= .{label}), since there's no URCL to link it with here", func.name)
+            }
+            FunctionBody::Deferred => fail!(entry, "{} never got a body", func.name),
+        }
+        Ok(None)
+    }
+
+    /// Looks up the func that a func pointer points to, checking that it takes and returns what the `icall` says.
+    fn func_pointer(
+        &self,
+        entry: &InstructionEntry<'a>,
+        ptr: &BigUint,
+        stack: StackBehaviour,
+    ) -> Result<&'r Function<'a>, SourceError<'a>> {
+        let Some(func) = ptr
+            .to_usize()
+            .and_then(|idx| self.funcs.get(idx))
+            .map(|name| &self.result.functions[name])
+        else {
+            fail!(entry, "{ptr} is not a function pointer");
+        };
+        if func.stack != stack {
+            fail!(
+                entry,
+                "icall with ({stack}) on a pointer to {}, which has ({})",
+                func.name,
+                func.stack
+            );
+        }
+        Ok(func)
+    }
+
+    /// Runs the body of an `inst`. Returns the outputs, and whether it jumped to the branch destination.
+    fn run_urcl(
+        &mut self,
+        entry: &InstructionEntry<'a>,
+        instructions: &[urcl::InstructionEntry<'a>],
+        input: impl Iterator<Item = urcl::Register<'a>>,
+        args: Vec<BigUint>,
+        output: &[urcl::Register<'a>],
+    ) -> Result<(Vec<BigUint>, bool), SourceError<'a>> {
+        use urcl::{BranchDestination, Destination, Register, Source};
+
+        let mut regs = HashMap::<Register<'a>, BigUint>::new();
+        for (reg, value) in input.zip(args) {
+            regs.insert(reg, value);
+        }
+        regs.remove(&Register::Index(0));
+        let read = |machine: &Self, regs: &HashMap<_, BigUint>, source: &Source<'a>| match source {
+            Source::Register(reg) => Some(regs.get(reg).cloned().unwrap_or_default()),
+            Source::Literal(lit) => machine.literal(lit),
+        };
+        // relative to the instruction doing the jumping, and `None` for the branch destination
+        let jump = |here: usize, dest: &BranchDestination| match *dest {
+            BranchDestination::Relative(n) => Some(here.wrapping_add_signed(n)),
+            BranchDestination::BranchLabel => None,
+            BranchDestination::TemporaryLabel(_) => {
+                unreachable!("Temporary label should have been lowered already.")
+            }
+        };
+
+        let mut pc = 0;
+        while let Some(inst) = instructions.get(pc) {
+            // positioned at the URCL instruction, since that's the one that failed
+            macro_rules! fail {
+                ($($t:tt)*) => {
+                    return Err(err!(@ Some(inst.pos.clone()), $($t)*))
+                };
+            }
+            self.step(entry)?;
+            let here = pc;
+            pc += 1;
+            match &inst.instruction {
+                urcl::Instruction::In { dest, port } => match self.ports.read(port, self.bits) {
+                    Some(Ok(value)) => {
+                        regs.insert(*dest, value);
+                    }
+                    Some(Err(err)) => fail!("Can't read from %{port}: {err}"),
+                    None => fail!("Port %{port} is not supported"),
+                },
+                urcl::Instruction::Out { port, source } => {
+                    let Some(value) = read(self, &regs, source) else {
+                        fail!("Can't evaluate {source}");
+                    };
+                    match self.ports.write(port, &value, self.bits) {
+                        Some(Ok(())) => (),
+                        Some(Err(err)) => fail!("Can't write to %{port}: {err}"),
+                        None => fail!("Port %{port} is not supported"),
+                    }
+                }
+                urcl::Instruction::Jmp { dest } => match jump(here, dest) {
+                    Some(dest) => pc = dest,
+                    None => return Ok((Vec::new(), true)),
+                },
+                urcl::Instruction::Generic { op, dest, sources } => {
+                    let mut values = Vec::new();
+                    for source in sources {
+                        match read(self, &regs, source) {
+                            Some(value) => values.push(value),
+                            None => fail!("Can't evaluate {source}"),
+                        }
+                    }
+                    match dest {
+                        Destination::Branch(dest) => {
+                            match fold::condition(op, &values, self.bits) {
+                                Some(true) => match jump(here, dest) {
+                                    Some(dest) => pc = dest,
+                                    None => return Ok((Vec::new(), true)),
+                                },
+                                Some(false) => (),
+                                None => {
+                                    fail!("{op} is not a branch instruction the interpreter knows")
+                                }
+                            }
+                        }
+                        Destination::Register(reg) => {
+                            let target = regs.get(reg).cloned().unwrap_or_default();
+                            macro_rules! mem {
+                                ($addr:expr) => {{
+                                    let addr = $addr;
+                                    match addr.to_usize() {
+                                        Some(addr) if addr < self.memory.len() => addr,
+                                        _ => fail!("Memory access out of bounds at address {addr} (memory is {} words)", self.memory.len()),
+                                    }
+                                }};
+                            }
+                            let value = match (op.to_ascii_uppercase().as_str(), values.as_slice())
+                            {
+                                ("MOV" | "IMM", [a]) => a.clone(),
+                                ("LOD", [a]) => self.memory[mem!(a)].clone(),
+                                ("LLOD", [a, b]) => self.memory[mem!(self.wrap(a + b))].clone(),
+                                ("STR", [a]) => {
+                                    let addr = mem!(&target);
+                                    self.memory[addr] = a.clone();
+                                    continue;
+                                }
+                                ("LSTR", [a, b]) => {
+                                    let addr = mem!(self.wrap(&target + a));
+                                    self.memory[addr] = b.clone();
+                                    continue;
+                                }
+                                ("CPY", [a]) => {
+                                    let (dest, src) = (mem!(&target), mem!(a));
+                                    self.memory[dest] = self.memory[src].clone();
+                                    continue;
+                                }
+                                _ => match fold::evaluate(op, &values, self.bits) {
+                                    Some(value) => value,
+                                    None => fail!("Can't evaluate {}", inst.instruction),
+                                },
+                            };
+                            if *reg != Register::Index(0) {
+                                regs.insert(*reg, value);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let outputs = output
+            .iter()
+            .map(|reg| regs.get(reg).cloned().unwrap_or_default())
+            .collect();
+        Ok((outputs, false))
+    }
+}
+
+fn data_len(val: &DataLiteral) -> usize {
+    match val {
+        DataLiteral::Literal(_) => 1,
+        DataLiteral::Array(items) => items.iter().map(|(_, item)| data_len(item)).sum(),
+        DataLiteral::String(string) => string.chars().len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::*;
+
+    /// Runs a program in both the interpreter and the emulator, and checks they agree.
+    #[track_caller]
+    fn run_both(source: &str) -> String {
+        let interpreted = interpret_source(source);
+        assert_eq!(interpreted, run_source(source));
+        interpreted
+    }
+
+    #[test]
+    fn calls_with_locals() {
+        let source = format!(
+            "{HEADERS}
+            func $main {{
+                const 3 const 4 call $sumsq out %numb
+                const 1 const 2 call $sumsq out %numb
+            }}
+            func $sumsq 2 -> 1 + 1 {{
+                get 0 get 0 mult set 2
+                get 1 get 1 mult get 2 add
+                ret
+            }}"
+        );
+        assert_eq!(run_both(&source), "255");
+    }
+
+    #[test]
+    fn ref_with_load_and_store() {
+        let source = format!(
+            "{HEADERS}
+            func $main {{
+                const 5 call $poke
+            }}
+            func $poke 1 -> 0 + 1 {{
+                ref 0 load out %numb
+                ref 1 const 7 store
+                ref 0 get 1 store
+                get 0 out %numb
+                ret
+            }}"
+        );
+        assert_eq!(run_both(&source), "57");
+    }
+
+    #[test]
+    fn custom_inst_with_branch() {
+        let source = format!(
+            "{HEADERS}
+            inst clamp <&a> -> &out {{
+                MOV &out &a
+                BRL :done &a 10
+                IMM &out 10
+                :done
+            }}
+            branch small <&a> -> :dest {{ BRL :dest &a 5 }}
+            func $main {{
+                const 3 clamp out %numb
+                const 30 clamp out %numb
+                const 4 small branch :yes
+                const 0 out %numb
+                halt
+                height 0
+                label :yes
+                const 1 out %numb
+            }}"
+        );
+        assert_eq!(run_both(&source), "3101");
+    }
+
+    #[test]
+    fn wraps_around_at_bits_8() {
+        let source = format!(
+            "{HEADERS}
+            func $main {{
+                const 255 inc out %numb
+                const 0 dec out %numb
+                const 200 const 100 add out %numb
+                const 16 const 17 mult out %numb
+            }}"
+        );
+        assert_eq!(run_both(&source), "02554416");
+    }
+}
@@ -0,0 +1,1622 @@
+mod callstack;
+mod common;
+pub mod diagnostics;
+pub mod emulator;
+mod fold;
+mod imports;
+pub mod interpreter;
+pub mod lints;
+pub mod mangle;
+pub mod peephole;
+mod permutation;
+pub mod ports;
+mod reachability;
+pub mod urcl;
+pub mod ursl;
+
+#[cfg(test)]
+mod testing;
+
+pub use common::*;
+pub use imports::*;
+pub use permutation::*;
+use reachability::Reachable;
+
+use clap::Parser;
+use non_empty_vec::ne_vec;
+use num::{BigUint, Num};
+use std::{
+    borrow::Cow,
+    cmp::{self, Ordering},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{self, Debug, Display, Formatter},
+    io::{self, Write},
+};
+use tree_sitter::{Node, Tree};
+use tree_sitter_highlight::{Highlight, HighlightConfiguration, HighlightEvent, Highlighter};
+
+/// The source of the prelude, which defines most of the "builtin" instructions.
+pub const PRELUDE: &str = include_str!("prelude.ursl");
+
+/// The path reported for errors in the prelude.
+pub const PRELUDE_PATH: &str = if cfg!(debug_assertions) {
+    // This is useful for debugging errors in the prelude
+    "src/prelude.ursl"
+} else {
+    // But outside of writing the compiler, the internal path to
+    // the prelude makes no sense to expose when in release mode
+    "<prelude>"
+};
+
+pub trait NodeExt<'a> {
+    fn pos(&self, unit: &'a CompilationUnit<'a>) -> Position<'a>;
+    fn text(&self, unit: &'a CompilationUnit<'a>) -> &'a str;
+    fn field(&self, name: &str, unit: &'a CompilationUnit<'a>) -> Self;
+}
+
+impl<'a> NodeExt<'a> for Node<'a> {
+    fn pos(&self, unit: &'a CompilationUnit<'a>) -> Position<'a> {
+        Position {
+            unit,
+            range: self.range(),
+        }
+    }
+
+    fn text(&self, unit: &'a CompilationUnit<'a>) -> &'a str {
+        &unit.source[self.byte_range()]
+    }
+
+    fn field(&self, name: &str, unit: &'a CompilationUnit<'a>) -> Self {
+        self.child_by_field_name(name).unwrap_or_else(|| {
+            panic!(
+                "Syntax Error: expected a field `{name}` as child of `{}` at {}",
+                self.kind(),
+                self.pos(unit)
+            )
+        })
+    }
+}
+
+#[derive(Parser, Debug, Default, Clone)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// Emits strings are arrays of their characters. Most URCL compilers do not support strings, so this should work on all URCL compilers.
+    #[clap(short = 's', long)]
+    pub emit_strings_as_chars: bool,
+
+    /// Flattens arrays before emitting them. Most URCL compilers do not support nested arrays, so this should work on all URCL compilers.
+    #[clap(short = 'a', long)]
+    pub flatten_arrays: bool,
+
+    /// Parses escape sequences and emits the exact codepoint in the output. This will break some URCL compilers since the URCL code can then contain null bytes and newlines in char literals.
+    #[clap(short = 'c', long)]
+    pub emit_chars_literally: bool,
+
+    /// Emits char literals as numeric literals corresponding to their char codes. This should work with all URCL compilers. Both -c or -C are optional, and without either, char literals are left alone. URCL does not officially support char literals, which is why these are useful
+    #[clap(short = 'C', long)]
+    pub emit_chars_as_numbers: bool,
+
+    /// Print lowering of code before translation to URCL, and include additional details in comments in code output.
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// Allocates locals in bulk by subtracting the desired amount from the stack pointer. By default, they are overwritten to be zero when used. Bulk allocation will be somewhat faster, especially with many locals, but changes code behaviour. As such, this is an optimization that is only safe when your code definitely assigns locals before reading them. Some URCL environments may check for stack overflow with PSH, and they may not catch stack overflows with this option set either. As such, this is an optimization that should only be done when you're sure your code is non-recursive and you want to prioritize stack code size at all costs
+    #[clap(long)]
+    pub garbage_initialized_locals: bool,
+
+    /// Do not import prelude. Only the intrinsic instructions are predefined: const, in, out, jump, branch, halt, call, ret, get, set
+    #[clap(long)]
+    pub no_prelude: bool,
+
+    /// Do not enforce $main to exist or have a particular signature. Do not call $main at the start
+    #[clap(long)]
+    pub no_main: bool,
+
+    /// Always emit this function, even if $main never uses it. Can be given several times. Everything else that can't be reached from $main or a kept function is left out of the output. With --no-main and no kept functions, nothing is left out
+    #[clap(long, value_name = "FUNC")]
+    pub keep: Vec<String>,
+
+    /// Never use more than this many registers. Whatever doesn't fit is spilled to the callstack, which is slower, but works on targets with very few registers.
+    #[clap(long)]
+    pub max_regs: Option<usize>,
+
+    /// Pass up to this many args in registers instead of on the callstack, for funcs that are only ever called directly. Funcs whose address is taken, exported or kept funcs, and args that are used with `ref` still use the callstack. This can't be combined with --max-regs or --no-main.
+    #[clap(long, value_name = "N", conflicts_with_all = &["max-regs", "no-main"])]
+    pub register_args: Option<usize>,
+
+    /// Don't apply this peephole optimization to the output. Can be given several times.
+    #[clap(long, arg_enum, value_name = "RULE")]
+    pub no_peephole: Vec<peephole::PeepholeRule>,
+
+    /// Don't report this lint at all. Can be given several times.
+    #[clap(short = 'A', long, arg_enum, value_name = "LINT")]
+    pub allow: Vec<lints::Lint>,
+
+    /// Report this lint as a warning, which is the default for all of them. Overrides -A. Can be given several times.
+    #[clap(short = 'W', long, arg_enum, value_name = "LINT")]
+    pub warn: Vec<lints::Lint>,
+
+    /// Report this lint as an error, so the compilation fails. Overrides -A and -W. Can be given several times.
+    #[clap(short = 'D', long, arg_enum, value_name = "LINT")]
+    pub deny: Vec<lints::Lint>,
+}
+
+#[derive(Clone)]
+pub struct Headers {
+    pub bits: Bits,
+    pub minheap: BigUint,
+    /// `None` if it wasn't declared, and should be computed from the callstack.
+    pub minstack: Option<BigUint>,
+}
+
+/// The `bits` header, which doesn't have to be one exact word size.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Bits {
+    /// `bits 8` or `bits == 8`
+    Exactly(u64),
+    /// `bits >= 8`
+    AtLeast(u64),
+    /// `bits <= 8`
+    AtMost(u64),
+    /// No `bits` header at all, so the program works with any word size.
+    Any,
+}
+
+impl Bits {
+    /// The word size, if it's known exactly. Anything that depends on wrapping around, like constant folding or
+    /// `@MAX`, can only be done at compile time with this.
+    pub fn exact(self) -> Option<u64> {
+        match self {
+            Self::Exactly(bits) => Some(bits),
+            _ => None,
+        }
+    }
+
+    /// The smallest word size the program has to work with. Without a lower bound, that's URCL's default of 8.
+    pub fn minimum(self) -> u64 {
+        match self {
+            Self::Exactly(bits) | Self::AtLeast(bits) => bits,
+            Self::AtMost(bits) => bits.min(8),
+            Self::Any => 8,
+        }
+    }
+}
+
+impl Display for Bits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exactly(bits) => write!(f, "{bits}"),
+            Self::AtLeast(bits) => write!(f, ">= {bits}"),
+            Self::AtMost(bits) => write!(f, "<= {bits}"),
+            Self::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// Everything the compiler knows about a program after parsing and checking it, ready to be emitted.
+pub struct CompileResult<'a> {
+    headers: Headers,
+    defs: Vec<(&'a str, DataLiteral<'a>)>,
+    functions: BTreeMap<&'a str, Function<'a>>,
+}
+
+impl<'a> CompileResult<'a> {
+    /// Every function and instruction, including the prelude, by name.
+    pub fn functions(&self) -> &BTreeMap<&'a str, Function<'a>> {
+        &self.functions
+    }
+}
+
+/// Parses a source file with a parser that's already set to URSL.
+pub fn parse_tree(parser: &mut tree_sitter::Parser, path: &str, source: &str) -> Tree {
+    parser
+        .parse(source, None)
+        .unwrap_or_else(|| panic!("Parsing fucked up real bad in {path}. Didn't even give me a syntax tree. This should be impossible."))
+}
+
+/// A single parsed source file.
+pub struct CompilationUnit<'a> {
+    path: &'a str,
+    source: &'a str,
+    highlighted_source: Vec<String>,
+    tree: Tree,
+}
+
+impl CompilationUnit<'static> {
+    /// Parses the built-in prelude, without any highlighting.
+    pub fn prelude() -> Self {
+        CompilationUnit::parse(PRELUDE_PATH, PRELUDE)
+    }
+}
+
+impl<'a> CompilationUnit<'a> {
+    /// Parses a source file without any syntax highlighting. This is what you want when using the compiler as a library.
+    pub fn parse(path: &'a str, source: &'a str) -> Self {
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(tree_sitter_ursl::language())
+            .expect("Failed to set language. For sure unreachable.");
+        CompilationUnit::unhighlighted(path, source, parse_tree(&mut parser, path, source))
+    }
+
+    /// Like [`CompilationUnit::parse`], but for a file from [`load_sources`], which was already parsed to find its imports.
+    pub fn from_file(file: &'a SourceFile) -> Self {
+        CompilationUnit::unhighlighted(&file.path, &file.source, file.tree.clone())
+    }
+
+    fn unhighlighted(path: &'a str, source: &'a str, tree: Tree) -> Self {
+        CompilationUnit {
+            path,
+            source,
+            highlighted_source: source.split('\n').map(String::from).collect(),
+            tree,
+        }
+    }
+
+    /// Highlights an already parsed source file with ANSI escape codes for nicer error messages.
+    pub fn new(
+        path: &'a str,
+        source: &'a str,
+        tree: Tree,
+        highlighter: &mut Highlighter,
+        highlight_config: &HighlightConfiguration,
+        formats: &[impl AsRef<str>],
+    ) -> Self {
+        let mut highlighted_source = Vec::new();
+        let mut last_line = String::new();
+        let mut colors = ne_vec!["\x1b[0m"];
+
+        for event in highlighter
+            .highlight(highlight_config, source.as_bytes(), None, |_| None)
+            .unwrap()
+            .map(Result::unwrap)
+        {
+            match event {
+                HighlightEvent::HighlightStart(Highlight(u)) => {
+                    colors.push(formats[u].as_ref());
+                    last_line.push_str(colors.last());
+                }
+                HighlightEvent::Source { start, end } => {
+                    let source = &source[start..end];
+                    let (first, rest) = source
+                        .split_once('\n')
+                        .map(|(first, rest)| (first, Some(rest)))
+                        .unwrap_or((source, None));
+                    last_line.push_str(first);
+                    if let Some(rest) = rest {
+                        for line in rest.split('\n') {
+                            highlighted_source.push(last_line);
+                            last_line = String::new();
+                            last_line.push_str(colors.last());
+                            last_line.push_str(line);
+                        }
+                    }
+                }
+                HighlightEvent::HighlightEnd => {
+                    colors.pop();
+                    last_line.push_str(colors.last());
+                }
+            }
+        }
+        highlighted_source.push(last_line);
+        CompilationUnit {
+            path,
+            source,
+            tree,
+            highlighted_source,
+        }
+    }
+
+    pub fn path(&self) -> &'a str {
+        self.path
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// The lines of the source, possibly with ANSI color codes if this unit was created with [`CompilationUnit::new`].
+    pub fn highlighted_lines(&self) -> &[String] {
+        &self.highlighted_source
+    }
+}
+
+/// Compiles the given units to URCL text. This is the main entry point when using the compiler as a library.
+///
+/// Units are compiled in order, so the prelude (if any) should come first, and the headers are read from the last unit.
+/// If there are any errors, no URCL is emitted, and the warnings are returned along with them. Otherwise, warnings are dropped.
+pub fn compile_to_string<'a>(
+    args: &Args,
+    units: &[&'a CompilationUnit<'a>],
+) -> Result<String, Vec<SourceError<'a>>> {
+    let (result, mut errors) = compile(args, units);
+    if errors.iter().any(SourceError::is_error) {
+        return Err(errors);
+    }
+    let mut output = Vec::new();
+    errors.extend(emit(&mut output, args, result).expect("Writing to a Vec<u8> can't fail"));
+    if errors.iter().any(SourceError::is_error) {
+        return Err(errors);
+    }
+    Ok(String::from_utf8(output).expect("Emitted URCL is always valid UTF-8"))
+}
+
+/// Parses and checks the given units in order. The headers are read from the last unit.
+///
+/// The result is always returned, even if there are errors, but emitting it in that case will likely panic or produce garbage.
+pub fn compile<'a>(
+    args: &Args,
+    units: &[&'a CompilationUnit<'a>],
+) -> (CompileResult<'a>, Vec<SourceError<'a>>) {
+    let main = units
+        .last()
+        .expect("There should be at least one compilation unit");
+    let mut errors = Vec::new();
+    let headers = parse_headers(
+        main.tree
+            .root_node()
+            .children_by_field_name("headers", &mut main.tree.walk()),
+        main,
+    )
+    .extend_into(&mut errors);
+    let mut defs = Vec::new();
+    // only for linting unused data in the main file
+    let mut data_nodes = Vec::new();
+    let mut functions = BTreeMap::new();
+    let mut signatures = HashMap::new();
+    // these can be used anywhere, so they're all parsed before anything else
+    let mut conventions = HashMap::new();
+    for unit in units {
+        for node in unit
+            .tree
+            .root_node()
+            .children_by_field_name("conventions", &mut unit.tree.walk())
+        {
+            let convention = parse_convention(node, unit).extend_into(&mut errors);
+            if CallingConvention::BUILTIN
+                .iter()
+                .any(|builtin| builtin.name == convention.name)
+            {
+                err!(errors; unit; node, "Calling convention \"{convention}\" is builtin, and can't be redeclared");
+            } else if conventions.insert(convention.name, convention).is_some() {
+                err!(errors; unit; node, "Duplicate calling convention \"{convention}\"");
+            }
+        }
+    }
+    for unit in units {
+        if !std::ptr::eq(*unit, *main) {
+            for node in unit
+                .tree
+                .root_node()
+                .children_by_field_name("headers", &mut unit.tree.walk())
+            {
+                err!(errors; unit; node, "Headers are only allowed in the main file ({})", main.path);
+            }
+        }
+
+        for node in unit
+            .tree
+            .root_node()
+            .children_by_field_name("data", &mut unit.tree.walk())
+        {
+            let label = node.field("label", unit).field("name", unit).text(unit);
+            if std::ptr::eq(*unit, *main) {
+                data_nodes.push((label, node));
+            }
+            let literal =
+                parse_data_literal(node.field("value", unit), unit).extend_into(&mut errors);
+            defs.push((
+                label,
+                lower_data_literal(args, &headers, literal, node, unit).extend_into(&mut errors),
+            ));
+        }
+
+        if args.verbose {
+            println!();
+            println!("=== Declarations after parsing {} ===", unit.path);
+            println!();
+            for (label, val) in &defs {
+                println!(".{label} {val}");
+            }
+            println!();
+        }
+
+        errors.extend(parse_functions(
+            args,
+            &headers,
+            unit.tree
+                .root_node()
+                .children_by_field_name("code", &mut unit.tree.walk()),
+            &mut functions,
+            &mut signatures,
+            &conventions,
+            unit,
+        ));
+    }
+
+    for func in functions.values() {
+        if let FunctionBody::Deferred = func.body {
+            err!(errors; func.unit; func.node, "function {} is declared, but never given a body. Declare it with extern \"URSL\" if this is intentional", func.name);
+        }
+    }
+    // a tail call returns straight to the caller, so both funcs have to return the same way
+    let returns_in = |func: &Function| match func.body {
+        FunctionBody::Ursl {
+            export: Some((call_convention, _)),
+            ..
+        } => call_convention.returns,
+        _ => CallingConvention::URSL.returns,
+    };
+    for func in functions.values() {
+        let FunctionBody::Ursl {
+            ref instructions, ..
+        } = func.body
+        else {
+            continue;
+        };
+        for entry in instructions {
+            let callee_returns = match entry.instruction {
+                ursl::Instruction::TailCall(name) => match functions.get(name) {
+                    Some(callee @ Function {
+                        body: FunctionBody::Ursl { .. },
+                        ..
+                    }) => returns_in(callee),
+                    // already an error
+                    Some(Function {
+                        body: FunctionBody::Deferred,
+                        ..
+                    })
+                    | None => continue,
+                    Some(_) => {
+                        err!(errors; entry.unit; entry.node, "tailcall can only jump to a func, but {name} isn't one");
+                        continue;
+                    }
+                },
+                ursl::Instruction::TailIndirectCall(_) => CallingConvention::URSL.returns,
+                _ => continue,
+            };
+            if callee_returns != returns_in(func) {
+                err!(errors; entry.unit; entry.node, "{} can't be used here, since {} and the func it jumps to return in different registers", entry.instruction, func.name);
+            }
+        }
+    }
+
+    if args.no_main {
+        // ignore these checks lol
+    } else if let Some(main) = functions.get("$main") {
+        if main.stack.input != 0 {
+            err!(errors; main.unit; main.node.field("stack", main.unit).field("params", main.unit), "$main may not take any arguments");
+        }
+        if main.stack.output != 0 {
+            err!(errors; main.unit; main.node.field("stack", main.unit).field("returns", main.unit), "$main may not return any values");
+        }
+    } else {
+        err!(errors; None, "No $main function")
+    };
+    for name in args.keep.iter() {
+        if !functions.contains_key(name.as_str()) {
+            err!(errors; None, "--keep {name}: there is no function with that name")
+        }
+    }
+    let mut result = CompileResult {
+        headers,
+        defs,
+        functions,
+    };
+    // other files are usually libraries, where it's fine if not everything is used
+    let reachable = Reachable::find(args, &result);
+    for func in result.functions.values() {
+        if std::ptr::eq(func.unit, *main) && !reachable.functions.contains(func.name) {
+            let kind = match func.body {
+                FunctionBody::Ursl { .. } => "func",
+                FunctionBody::Extern(..) => "extern func",
+                FunctionBody::Deferred => continue,
+                _ => "inst",
+            };
+            lint!(errors; args; lints::Lint::UncalledFunc; @ Some(func.pos.clone()), "{kind} {} is never used", func.name);
+        }
+    }
+    for (label, node) in data_nodes {
+        if !reachable.data.contains(label) {
+            lint!(errors; args; lints::Lint::UnusedData; main; node.field("label", main), "Data label .{label} is never used");
+        }
+    }
+    // after the lints, since an inline func that's called isn't unused, even if nothing calls it anymore
+    errors.extend(ursl::inline_calls(&mut result.functions));
+
+    sort_errors(units, &mut errors);
+    (result, errors)
+}
+
+/// Sorts errors in the order of the units they're in, and then by line. Errors without a position come first.
+///
+/// [`compile`] already does this, but errors from elsewhere (like [`SourceFile::import_errors`]) need it again after they're added.
+pub fn sort_errors(units: &[&CompilationUnit], errors: &mut [SourceError]) {
+    let unit_index = |pos: &Position| units.iter().position(|unit| std::ptr::eq(*unit, pos.unit));
+    errors.sort_by(|a, b| {
+        if let Some(ref a) = a.pos {
+            if let Some(ref b) = b.pos {
+                unit_index(a)
+                    .cmp(&unit_index(b))
+                    .then(a.range.start_point.row.cmp(&b.range.start_point.row))
+            } else {
+                Ordering::Greater
+            }
+        } else if let Some(_) = b.pos {
+            Ordering::Less
+        } else {
+            Ordering::Equal
+        }
+    });
+}
+
+/// Writes the URCL for a compiled program.
+///
+/// Some errors can only be found while emitting, like code that doesn't fit in `--max-regs`. The output is complete regardless, but it's wrong if there are any.
+pub fn emit<'a>(
+    f: &mut impl Write,
+    args: &Args,
+    result: CompileResult<'a>,
+) -> io::Result<Vec<SourceError<'a>>> {
+    if result.headers.bits != Bits::Any {
+        writeln!(f, "BITS {}", result.headers.bits)?;
+    }
+    writeln!(f, "MINHEAP {}", result.headers.minheap)?;
+
+    let mut max_regs = 0;
+    let mut errors = Vec::new();
+    let reachable = Reachable::find(args, &result);
+
+    // with --no-main, anything could be called from outside of URSL
+    let register_args = match args.register_args {
+        // clap already rejects --register-args with those, but the library can be given anything
+        Some(max) if args.max_regs.is_none() && !args.no_main => {
+            let pointers = reachability::func_pointers(&result);
+            // a tail call reuses the frame of the func it's in for the args of the one it jumps to, so both of them
+            // have all their args on the callstack
+            let mut tail_calls = HashSet::new();
+            for func in result.functions.values() {
+                if let FunctionBody::Ursl {
+                    ref instructions, ..
+                } = func.body
+                {
+                    for entry in instructions {
+                        match entry.instruction {
+                            ursl::Instruction::TailCall(name) => {
+                                tail_calls.extend([func.name, name]);
+                            }
+                            ursl::Instruction::TailIndirectCall(_) => {
+                                tail_calls.insert(func.name);
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            result
+                .functions
+                .values()
+                .filter_map(|func| match func.body {
+                    FunctionBody::Ursl {
+                        ref instructions,
+                        export: None,
+                        ..
+                    } if !pointers.contains(func.name)
+                        && !tail_calls.contains(func.name)
+                        && !args.keep.iter().any(|name| name == func.name) =>
+                    {
+                        // an arg that's used with `ref` needs an address, so it and everything after it stays on the callstack
+                        let in_regs = instructions
+                            .iter()
+                            .filter_map(|entry| match entry.instruction {
+                                ursl::Instruction::Ref(idx) => Some(idx),
+                                _ => None,
+                            })
+                            .fold(cmp::min(max, func.stack.input), cmp::min);
+                        (in_regs != 0).then_some((func.name, in_regs))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+        _ => HashMap::new(),
+    };
+
+    let mut contents = Vec::new();
+    if !args.no_main {
+        writeln!(contents, "CAL .{}", mangle::function_name("$main"))?;
+        writeln!(contents, "HLT")?;
+    }
+
+    for (label, val) in result.defs {
+        if !reachable.data.contains(label) {
+            continue;
+        }
+        writeln!(contents, ".{}\nDW {val}", mangle::data_label(label))?;
+    }
+
+    for func in result.functions.values() {
+        if !reachable.functions.contains(func.name) {
+            continue;
+        }
+        if let FunctionBody::Ursl {
+            locals,
+            ref instructions,
+            ..
+        } = func.body
+        {
+            errors.extend(ursl::emit_instructions(
+                args,
+                &result.headers,
+                &mut contents,
+                &result.functions,
+                func,
+                locals,
+                instructions,
+                &register_args,
+                &mut max_regs,
+            )?);
+        }
+    }
+
+    let mut program = peephole::Program::parse(
+        &String::from_utf8(contents).expect("Emitted URCL is always valid UTF-8"),
+    );
+    program.optimize(&args.no_peephole);
+
+    let minstack = if args.no_main {
+        result.headers.minstack.clone().unwrap_or_else(|| {
+            errors.push(SourceError {
+                missing_header: true,
+                ..err!(@ None, "Missing header `minstack`. It can only be computed from $main, so it's required with --no-main")
+            });
+            BigUint::default()
+        })
+    } else {
+        match (
+            result.headers.minstack.clone(),
+            callstack::max_depth(&program, &result.functions, result.headers.bits.minimum()),
+        ) {
+            (Some(declared), Ok(needed)) if declared < needed.into() => {
+                err!(errors; None; declared, "The `minstack` header is {declared}, but the callstack can get {needed} words deep")
+            }
+            (Some(declared), Ok(_)) => declared,
+            (Some(declared), Err(unbounded)) => {
+                let pos = unbounded.func(&result.functions).map(|func| func.pos.clone());
+                lint!(errors; args; lints::Lint::UnboundedStack; @ pos, "Can't check that the `minstack` header is big enough, because {unbounded}");
+                declared
+            }
+            (None, Ok(needed)) => needed.into(),
+            (None, Err(unbounded)) => {
+                let pos = unbounded.func(&result.functions).map(|func| func.pos.clone());
+                errors.push(err!(@ pos, "Can't compute the `minstack` header, because {unbounded}. It has to be declared instead"));
+                BigUint::default()
+            }
+        }
+    };
+    writeln!(f, "MINSTACK {minstack}")?;
+    writeln!(f, "MINREG {max_regs}")?;
+    write!(f, "{program}")?;
+    Ok(errors)
+}
+
+fn parse_headers<'a>(
+    headers: impl Iterator<Item = Node<'a>>,
+    unit: &'a CompilationUnit<'a>,
+) -> (Headers, Vec<SourceError<'a>>) {
+    let mut errors = Vec::new();
+    let headers = headers.collect::<Vec<_>>();
+    macro_rules! parse_header {
+        ($header:expr, $name:ident, $default:expr) => {{
+            let value = $header.field("value", unit);
+            let parsed = value.text(unit).parse().unwrap_or_else(|err| {
+                err!(errors; unit; value; $default, "Invalid value for header `{}`: {err}", stringify!($name))
+            });
+            if $name.replace(parsed).is_some() {
+                err!(errors; unit; $header, "Duplicate header `{}`", stringify!($name));
+            }
+        }};
+    }
+    // the defaults are only used after an error, so that the rest of the file can still be checked
+    macro_rules! parse_headers {
+        ($($name:ident = $default:expr),*; $($optional:ident = $optional_default:expr),*) => {{
+            $(let mut $name = None;)*
+            $(let mut $optional = None;)*
+            for &header in &headers {
+                match header.kind() {
+                    $(stringify!($name) => parse_header!(header, $name, $default),)*
+                    $(stringify!($optional) => parse_header!(header, $optional, $optional_default),)*
+                    _ => unknown_node(header, unit),
+                }
+            }
+            $(let $name = $name.unwrap_or_else(|| {
+                errors.push(SourceError {
+                    missing_header: true,
+                    ..err!(@ None, "Missing header `{}` in {}", stringify!($name), unit.path)
+                });
+                $default
+            });)*
+            ($($name,)* $($optional,)*)
+        }};
+    }
+    // minstack can be left out, and then it's computed from the callstack when emitting
+    let (minheap, bits, minstack) = parse_headers!(minheap = BigUint::default(); bits = 8, minstack = BigUint::default());
+    let bits = match bits {
+        Some(bits) => {
+            let comparison = headers
+                .iter()
+                .rev()
+                .find(|header| header.kind() == "bits")
+                .and_then(|header| header.child_by_field_name("comparison"));
+            match comparison.map(|node| (node, node.text(unit))) {
+                None | Some((_, "==")) => Bits::Exactly(bits),
+                Some((_, ">=")) => Bits::AtLeast(bits),
+                Some((_, "<=")) => Bits::AtMost(bits),
+                Some((node, _)) => unknown_node(node, unit),
+            }
+        }
+        None => Bits::Any,
+    };
+    (
+        Headers {
+            bits,
+            minheap,
+            minstack,
+        },
+        errors,
+    )
+}
+
+/// The calling convention and label of an `extern func` or `export func`, which is either given like `= .label` or
+/// mangled the way that calling convention does it.
+fn parse_foreign_func<'a>(
+    node: Node<'a>,
+    name: &'a str,
+    stack: StackBehaviour,
+    types: Option<&(Vec<&'a str>, &'a str)>,
+    conventions: &HashMap<&'a str, CallingConvention<'a>>,
+    unit: &'a CompilationUnit<'a>,
+) -> ((CallingConvention<'a>, Cow<'a, str>), Vec<SourceError<'a>>) {
+    let mut errors = Vec::new();
+    let call_convention =
+        parse_call_convention(node.field("call_convention", unit), conventions, unit)
+            .extend_into(&mut errors);
+    let label = node
+        .child_by_field_name("label")
+        .map(|label| label.field("name", unit).text(unit));
+
+    let label: Cow<'_, str> = if let Some(label) = label {
+        if label.contains('.') {
+            err!(errors; unit; node, "Raw label name must not contain a dot ('.')");
+        }
+        label.into()
+    } else {
+        match call_convention.mangling {
+            Mangling::Ursl => mangle::function_name(name).into(),
+            Mangling::Name => name.into(),
+            Mangling::Prefix(prefix) => format!("{prefix}{}", &name[1..]).into(),
+            Mangling::Hexagn => match types {
+                Some(_) if name.contains('.') => {
+                    err!(errors; unit; node; name.into(), "Hexagn names can't contain a dot ('.'), so it needs a raw label")
+                }
+                Some((params, returns)) => {
+                    mangle::hexagn_function_name(name, params, returns).into()
+                }
+                None => {
+                    err!(errors; unit; node; name.into(), "Hexagn name mangling needs the types, like `func {name}(int, int) -> int`, or a raw label")
+                }
+            },
+        }
+    };
+
+    if !call_convention.multiple_returns && stack.output > 1 {
+        err!(errors; unit; node, "{call_convention} only supports single word returns. Stop.");
+    }
+    ((call_convention, label), errors)
+}
+
+/// The param types and return type of `func $name(int, int*) -> int`. The return type is `void` if it's left out.
+fn parse_hexagn_types<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (Vec<&'a str>, &'a str) {
+    let params = node
+        .children_by_field_name("param", &mut unit.tree.walk())
+        .map(|param| param.text(unit))
+        .collect();
+    let returns = node
+        .child_by_field_name("returns")
+        .map_or("void", |returns| returns.text(unit));
+    (params, returns)
+}
+
+fn parse_stack_sig<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (StackBehaviour, Vec<SourceError<'a>>) {
+    match node.child_by_field_name("stack") {
+        Some(node) => parse_stack(node, unit),
+        None => (stack!(0; -> 0), Vec::new()),
+    }
+}
+
+fn parse_stack<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (StackBehaviour, Vec<SourceError<'a>>) {
+    let mut errors = Vec::new();
+    let params = node.field("params", unit);
+    if params.kind() == "names" {
+        err!(errors; unit; params, "Args can only be named in the head of a func");
+    }
+    let stack = StackBehaviour {
+        input: parse_count(params, unit).extend_into(&mut errors),
+        output: parse_num(node.field("returns", unit), unit).extend_into(&mut errors),
+    };
+    (stack, errors)
+}
+
+/// The stack behaviour of a func head, which is the only place where the args can be named.
+fn parse_head_stack_sig<'a>(
+    head: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (StackBehaviour, Vec<SourceError<'a>>) {
+    let Some(node) = head.child_by_field_name("stack") else {
+        return (stack!(0; -> 0), Vec::new());
+    };
+    let mut errors = Vec::new();
+    let stack = StackBehaviour {
+        input: parse_count(node.field("params", unit), unit).extend_into(&mut errors),
+        output: parse_num(node.field("returns", unit), unit).extend_into(&mut errors),
+    };
+    (stack, errors)
+}
+
+fn parse_locals<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (usize, Vec<SourceError<'a>>) {
+    match node.child_by_field_name("locals") {
+        Some(node) => parse_count(node, unit),
+        None => (0, Vec::new()),
+    }
+}
+
+/// Args and locals are either a number, or a list of names like `(a b)` that also says how many there are.
+fn parse_count<'a>(
+    node: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (usize, Vec<SourceError<'a>>) {
+    match node.kind() {
+        "names" => (
+            node.children_by_field_name("items", &mut unit.tree.walk()).count(),
+            Vec::new(),
+        ),
+        _ => parse_num(node, unit),
+    }
+}
+
+fn parse_functions<'a>(
+    args: &Args,
+    headers: &Headers,
+    funcs: impl Iterator<Item = Node<'a>>,
+    functions: &mut BTreeMap<&'a str, Function<'a>>,
+    signatures: &mut HashMap<&'a str, (StackBehaviour, bool)>,
+    conventions: &HashMap<&'a str, CallingConvention<'a>>,
+    unit: &'a CompilationUnit<'a>,
+) -> Vec<SourceError<'a>> {
+    let mut errors = Vec::new();
+    // btreemap ensures deterministic ordering when writing output
+    let mut instruction_nodes = HashMap::new();
+
+    for node in funcs {
+        match node.kind() {
+            "deferred_func" => {
+                let name = node.field("name", unit).text(unit);
+                let stack = parse_stack_sig(node, unit).extend_into(&mut errors);
+                if let Some(f) = functions.get(&name) {
+                    if f.stack != stack {
+                        err!(errors; unit; node, "Conflicting stack behaviour, previously defined at {} with ({}), but here has ({})", f.pos, f.stack, stack);
+                    }
+                } else {
+                    functions.insert(
+                        name,
+                        Function {
+                            node,
+                            name,
+                            stack,
+                            body: FunctionBody::Deferred,
+                            pos: node.pos(unit),
+                            unit,
+                        },
+                    );
+                    signatures.insert(name, (stack, false));
+                }
+            }
+            "extern_func" => {
+                let name = node.field("name", unit).text(unit);
+                let types = node
+                    .child_by_field_name("types")
+                    .map(|types| parse_hexagn_types(types, unit));
+                let stack = match types {
+                    // every Hexagn type is a single word
+                    Some((ref params, returns)) => {
+                        stack!(params.len(); -> if returns == "void" { 0 } else { 1 })
+                    }
+                    None => parse_stack_sig(node, unit).extend_into(&mut errors),
+                };
+                let (call_convention, label) =
+                    parse_foreign_func(node, name, stack, types.as_ref(), conventions, unit)
+                        .extend_into(&mut errors);
+
+                let new_func = Function {
+                    node,
+                    name,
+                    stack,
+                    body: FunctionBody::Extern(call_convention, label),
+                    pos: node.pos(unit),
+                    unit,
+                };
+
+                if let Some(old_func) = functions.get_mut(name) {
+                    match old_func.body {
+                        FunctionBody::Deferred => {
+                            if old_func.stack != new_func.stack {
+                                err!(errors; unit; node, "Conflicting stack behaviour, previously defined at {} with ({}), but here has ({})", old_func.pos, old_func.stack, new_func.stack);
+                            } else {
+                                old_func.body = new_func.body;
+                                old_func.pos = new_func.pos;
+                                old_func.unit = new_func.unit;
+                            }
+                        }
+                        _ => {
+                            err!(errors; unit; node, "Duplicate func `{name}`, previously defined at {}", old_func.pos)
+                        }
+                    }
+                } else {
+                    functions.insert(name, new_func);
+                    signatures.insert(name, (stack, false));
+                }
+            }
+            "func" => {
+                let head = node.field("head", unit);
+                let stack = parse_head_stack_sig(head, unit).extend_into(&mut errors);
+                let locals = parse_locals(head, unit).extend_into(&mut errors);
+                let name = head.field("name", unit).text(unit); // don't trim $, that way it doesn't collide with insts
+                let export = node.child_by_field_name("call_convention").map(|_| {
+                    let export = parse_foreign_func(node, name, stack, None, conventions, unit)
+                        .extend_into(&mut errors);
+                    let convention = export.0;
+                    // the body is still emitted the URSL way, only the return registers can be moved afterwards
+                    if !(convention.reversed_args && convention.caller_saves && convention.caller_cleanup) {
+                        err!(errors; unit; node, "Can't export a func as \"{convention}\", since it has to take args with `args: reversed-push`, `caller-saves` and `cleanup: caller`");
+                    }
+                    export
+                });
+                let new_func = Function {
+                    node,
+                    name,
+                    stack,
+                    body: FunctionBody::Ursl {
+                        locals,
+                        instructions: Vec::new(),
+                        export,
+                        inline: node.child_by_field_name("inline").is_some(),
+                    },
+                    pos: head.pos(unit),
+                    unit,
+                };
+                if let Some(old_func) = functions.get_mut(name) {
+                    match old_func.body {
+                        FunctionBody::Deferred => {
+                            if old_func.stack != new_func.stack {
+                                err!(errors; unit; node, "Conflicting stack behaviour, previously defined at {} with ({}), but here has ({})", old_func.pos, old_func.stack, new_func.stack);
+                            } else {
+                                old_func.body = new_func.body;
+                                old_func.pos = new_func.pos;
+                                old_func.unit = new_func.unit;
+                            }
+                        }
+                        _ => {
+                            err!(errors; unit; head, "Duplicate func `{name}`, previously defined at {}", old_func.pos)
+                        }
+                    }
+                } else {
+                    functions.insert(name, new_func);
+                    signatures.insert(name, (stack, false));
+                }
+                instruction_nodes.insert(name, node);
+            }
+            "inst" => {
+                let head = node.field("head", unit);
+                let name = head.field("name", unit).text(unit);
+                if ["halt", "ret"].contains(&name) {
+                    err!(errors; unit; head.field("name", unit), "inst {name} is also defined as intrinsic");
+                }
+                let input = urcl::parse_input_stack_bindings(
+                    head.children_by_field_name("input", &mut unit.tree.walk()),
+                    unit,
+                )
+                .extend_into(&mut errors);
+                let output = urcl::parse_output_stack_bindings(
+                    head.children_by_field_name("output", &mut unit.tree.walk()),
+                    unit,
+                );
+                let stack = stack!(input.len(); -> output.len());
+                let instructions = urcl::parse_instructions(
+                    args,
+                    headers,
+                    node.children_by_field_name("instruction", &mut unit.tree.walk()),
+                    name,
+                    None,
+                    unit,
+                )
+                .extend_into(&mut errors);
+                let body = UrclMainBody {
+                    input,
+                    output,
+                    instructions,
+                    pos: head.pos(unit),
+                };
+                if let Some(Function {
+                    node: _,
+                    name: _,
+                    stack: old_stack,
+                    body: f_body,
+                    unit: _,
+                    pos: old_pos,
+                }) = functions.get_mut(name)
+                {
+                    if let FunctionBody::Urcl {
+                        overloads,
+                        branch: _,
+                        fold: _,
+                    } = f_body
+                    {
+                        if stack != *old_stack {
+                            err!(errors; unit; head, "Conflicting stack behaviour, previously defined at {} with ({}), but here has ({})", old_pos, old_stack, stack);
+                        }
+                        overloads.push(body);
+                    } else {
+                        err!(errors; unit; head, "inst {name} is also defined at {old_pos}");
+                    }
+                } else {
+                    functions.insert(
+                        name,
+                        Function {
+                            node,
+                            name,
+                            stack,
+                            body: FunctionBody::Urcl {
+                                overloads: vec![body],
+                                branch: None,
+                                fold: None,
+                            },
+                            pos: head.pos(unit),
+                            unit,
+                        },
+                    );
+                    signatures.insert(name, (stack, false));
+                }
+            }
+            "inst_branch" => {
+                let head = node.field("head", unit);
+                let name = head.field("name", unit).text(unit);
+                if ["halt", "ret"].contains(&name) {
+                    err!(errors; unit; head.field("name", unit), "inst {name} is also defined as intrinsic");
+                }
+                let input = urcl::parse_input_stack_bindings(
+                    head.children_by_field_name("input", &mut unit.tree.walk()),
+                    unit,
+                )
+                .extend_into(&mut errors);
+                let branch_destination = &head.field("label", unit).field("name", unit).text(unit);
+                let stack = stack!(input.len(); -> 1);
+                let instructions = urcl::parse_instructions(
+                    args,
+                    headers,
+                    node.children_by_field_name("instruction", &mut unit.tree.walk()),
+                    name,
+                    Some(branch_destination),
+                    unit,
+                )
+                .extend_into(&mut errors);
+                let branch = UrclBranchBody {
+                    input,
+                    instructions,
+                    pos: head.pos(unit),
+                };
+                if let Some(Function {
+                    node: _,
+                    name: _,
+                    stack: old_stack,
+                    body: f_body,
+                    unit: _,
+                    pos: old_pos,
+                }) = functions.get_mut(name)
+                {
+                    if let FunctionBody::Urcl {
+                        overloads: _,
+                        branch: branch_body,
+                        fold: _,
+                    } = f_body
+                    {
+                        if old_stack.input != stack.input {
+                            err!(errors; unit; head,
+                                "branch {name} is defined with a different signature than before. Here it has {} input items, but before it had {} input items. Previous definition at {old_pos}",
+                                stack.input, old_stack.input,
+                            );
+                        }
+                        if old_stack.output != stack.output {
+                            err!(errors; unit; head,
+                                "branch {name} is defined with a different signature than before. Here it has {} output items, but before it had {} output items. Previous definition at {old_pos}",
+                                stack.output, old_stack.output,
+                            );
+                        }
+                        if let Some(old_branch) = branch_body.replace(branch) {
+                            err!(errors; unit; head,
+                                "branch {name} is also defined at {}", old_branch.pos);
+                        } else {
+                            signatures.get_mut(name).unwrap().1 = true;
+                        }
+                    } else {
+                        err!(errors; unit; head, "inst {name} is also defined at {old_pos}");
+                    }
+                } else {
+                    functions.insert(
+                        name,
+                        Function {
+                            node,
+                            name,
+                            stack,
+                            body: FunctionBody::Urcl {
+                                overloads: vec![],
+                                branch: Some(branch),
+                                fold: None,
+                            },
+                            pos: head.pos(unit),
+                            unit,
+                        },
+                    );
+                    signatures.insert(name, (stack, true));
+                }
+            }
+            "inst_permutation" => {
+                let name = node.field("name", unit).text(unit);
+                if ["halt", "ret"].contains(&name) {
+                    err!(errors; unit; node.field("name", unit), "inst {name} is also defined as intrinsic");
+                }
+                if let Some(f) = functions.get(&name) {
+                    err!(errors; unit; node, "inst {name} is also defined at {}", f.pos);
+                }
+                let perm = parse_permutation_sig(node.field("permutation", unit), unit)
+                    .extend_into(&mut errors);
+                let stack = stack!(perm.input; -> perm.output.len());
+                functions.insert(
+                    name,
+                    Function {
+                        node,
+                        name,
+                        stack,
+                        body: FunctionBody::Permutation(perm),
+                        pos: node.pos(unit),
+                        unit,
+                    },
+                );
+                signatures.insert(name, (stack, false));
+            }
+            "dunder_unary" => {
+                let name = node.field("name", unit).text(unit);
+                let instruction = node.field("instruction", unit);
+                functions.insert(
+                    name,
+                    Function {
+                        node,
+                        name,
+                        stack: stack!(1; -> 1),
+                        body: FunctionBody::Urcl {
+                            overloads: urcl::__unary__(node, instruction, unit),
+                            branch: None,
+                            fold: Some(instruction.text(unit)),
+                        },
+                        pos: node.pos(unit),
+                        unit,
+                    },
+                );
+                signatures.insert(name, (stack!(1; -> 1), false));
+            }
+            "dunder_binary" => {
+                let name = node.field("name", unit).text(unit);
+                let instruction = node.field("instruction", unit);
+                functions.insert(
+                    name,
+                    Function {
+                        node,
+                        name,
+                        stack: stack!(2; -> 1),
+                        body: FunctionBody::Urcl {
+                            overloads: urcl::__binary__(node, instruction, unit),
+                            branch: None,
+                            fold: Some(instruction.text(unit)),
+                        },
+                        pos: node.pos(unit),
+                        unit,
+                    },
+                );
+                signatures.insert(name, (stack!(2; -> 1), false));
+            }
+            "dunder_branching" => {
+                let name = node.field("name", unit).text(unit);
+                let instruction = node.field("instruction", unit);
+                let branch = node.field("branch", unit);
+                functions.insert(
+                    name,
+                    Function {
+                        node,
+                        name,
+                        stack: stack!(2; -> 1),
+                        body: FunctionBody::Urcl {
+                            overloads: urcl::__binary__(node, instruction, unit),
+                            branch: Some(urcl::__branching__(node, branch, unit)),
+                            fold: Some(instruction.text(unit)),
+                        },
+                        pos: node.pos(unit),
+                        unit,
+                    },
+                );
+                signatures.insert(name, (stack!(2; -> 1), true));
+            }
+            _ => unknown_node(node, unit),
+        }
+    }
+
+    for func in functions.values_mut() {
+        match &mut func.body {
+            FunctionBody::Deferred => {
+                if args.verbose {
+                    println!("(deferred) func {} {};", func.name, func.stack);
+                }
+            }
+            FunctionBody::Extern(convention, label) => {
+                if args.verbose {
+                    println!(
+                        "extern \"{}\" func {} {} = {}\n",
+                        convention,
+                        func.name,
+                        func.stack,
+                        label
+                    );
+                }
+            }
+            FunctionBody::Ursl {
+                locals,
+                instructions,
+                ..
+            } => {
+                let locals = *locals;
+                // Should be `None` if the instruction is defined in an earlier compilation unit
+                if let Some(node) = instruction_nodes.remove(func.name) {
+                    errors.extend(ursl::parse_instructions(
+                        args,
+                        headers,
+                        &signatures,
+                        conventions,
+                        func.node.clone(),
+                        node.children_by_field_name("instruction", &mut unit.tree.walk())
+                            .collect(),
+                        func.name,
+                        func.stack.input,
+                        func.stack.input + locals,
+                        func.stack.output,
+                        instructions,
+                        unit,
+                    ));
+                }
+                if args.verbose {
+                    println!("func {} : {} + {locals} {{", func.name, func.stack);
+                    for entry in instructions {
+                        println!("  {}", entry.instruction);
+                        match entry.instruction {
+                            ursl::Instruction::Ret
+                            | ursl::Instruction::Halt
+                            | ursl::Instruction::TailCall(_)
+                            | ursl::Instruction::TailIndirectCall(_)
+                            | ursl::Instruction::Jump(_)
+                            | ursl::Instruction::Branch(_, _) => println!(),
+                            _ => (),
+                        }
+                    }
+                    println!("}}");
+                }
+            }
+            FunctionBody::Urcl {
+                overloads, branch, ..
+            } => {
+                if args.verbose {
+                    for UrclMainBody {
+                        input,
+                        output,
+                        instructions,
+                        pos: _,
+                    } in overloads
+                    {
+                        print!("inst {}{input}", func.name);
+                        if !output.is_empty() {
+                            print!(" ->{output}");
+                        }
+                        println!(" {{");
+                        for entry in instructions {
+                            println!("  {}", entry.instruction)
+                        }
+                        println!("}}");
+                    }
+                    if let Some(UrclBranchBody {
+                        input,
+                        instructions,
+                        pos: _,
+                    }) = branch
+                    {
+                        println!("branch {}{input} {{", func.name);
+                        for entry in instructions {
+                            println!("  {}", entry.instruction)
+                        }
+                        println!("}}")
+                    }
+                }
+            }
+            FunctionBody::Permutation(perm) => {
+                if args.verbose {
+                    println!("inst {} {perm}", func.name);
+                }
+            }
+        }
+        if args.verbose {
+            println!();
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn missing_headers_are_flagged() {
+        let prelude = CompilationUnit::prelude();
+        let unit = CompilationUnit::parse("test.ursl", "func $main {}");
+        let (_, errors) = compile(&Args::default(), &[&prelude, &unit]);
+        let missing = errors
+            .iter()
+            .filter(|error| error.missing_header)
+            .map(|error| error.message.as_str())
+            .collect::<Vec<_>>();
+        // bits is optional, so only minheap is missing
+        assert_eq!(missing, ["Missing header `minheap` in test.ursl"]);
+        assert!(errors.iter().all(|error| error.missing_header));
+    }
+
+    #[test]
+    fn missing_bits_falls_back_to_8() {
+        let urcl = compile_source("minheap 16\nfunc $main { const 255 inc out %numb }").unwrap();
+        assert!(!urcl.contains("BITS"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "0");
+    }
+
+    #[test]
+    fn register_args_past_the_limit_stay_on_the_callstack() {
+        let args = Args {
+            register_args: Some(2),
+            ..Args::default()
+        };
+        let urcl = compile_with(
+            &args,
+            &format!(
+                "{HEADERS}
+                func $double 1 -> 1 {{ get 0 get 0 add ret }}
+                func $f 4 -> 1 {{
+                    get 0 inc set 0
+                    get 1 call $double set 1
+                    get 3 inc set 3
+                    get 0 get 1 add get 2 add get 3 add
+                    ret
+                }}
+                func $main {{ const 1 const 2 const 3 const 4 call $f out %numb }}"
+            ),
+        )
+        .unwrap();
+        // only $2 and $3 are pushed, the rest go in R1 and R2
+        assert_eq!(
+            func_body(&urcl, "$main").matches("PSH").count(),
+            2,
+            "{urcl}"
+        );
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "14");
+    }
+
+    #[test]
+    fn register_args_skip_funcs_used_as_pointers() {
+        let source = format!(
+            "{HEADERS}minstack 16
+            func $sub 2 -> 1 {{ get 0 get 1 sub ret }}
+            func $main {{
+                const 9 const 4 call $sub out %numb
+                const $sub const 7 const 2 icall 2 -> 1 out %numb
+            }}"
+        );
+        let args = Args {
+            register_args: Some(2),
+            ..Args::default()
+        };
+        let with = compile_with(&args, &source).unwrap();
+        let without = compile_source(&source).unwrap();
+        assert_eq!(func_body(&with, "$sub"), func_body(&without, "$sub"));
+        assert_eq!(func_body(&with, "$main"), func_body(&without, "$main"));
+        assert_eq!(emulate_urcl(&with).unwrap(), "55");
+    }
+
+    #[test]
+    fn custom_conventions_change_the_call() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}minstack 16
+            convention \"Mine\" {{
+                args: push;
+                returns: $3;
+                callee-saves;
+                cleanup: callee;
+                label-prefix: \"mine_\";
+            }}
+            extern \"Mine\" func $sub 2 -> 1;
+            func $main {{ const 9 const 4 call $sub out %numb }}"
+        ))
+        .unwrap();
+        let main = func_body(&urcl, "$main");
+        assert!(main.contains("PSH 9\nPSH 4\nCAL .mine_sub\n"), "{urcl}");
+        assert!(!main.contains("ADD SP SP"), "{urcl}");
+        assert!(main.contains("OUT %numb $3"), "{urcl}");
+        // the first arg is the deepest, and the callee pops both of them
+        let linked = format!(
+            "{urcl}
+            .mine_sub
+            LLOD $3 SP 2
+            LLOD $1 SP 1
+            SUB $3 $3 $1
+            POP $1
+            ADD SP SP 2
+            PSH $1
+            RET"
+        );
+        assert_eq!(emulate_urcl(&linked).unwrap(), "5");
+    }
+
+    #[test]
+    fn malformed_conventions_are_errors() {
+        assert_error(
+            &format!(
+                "{HEADERS}
+                convention \"Bad\" {{ returns: $0; }}
+                func $main {{ }}"
+            ),
+            "Expected a register like `$1` or `$1..` for returns, but got `$0`",
+        );
+        assert_error(
+            &format!(
+                "{HEADERS}
+                convention \"URCL++\" {{ }}
+                func $main {{ }}"
+            ),
+            "Calling convention \"URCL++\" is builtin, and can't be redeclared",
+        );
+    }
+
+    #[test]
+    fn max_regs_spills_a_deep_expression() {
+        let source = format!(
+            "{HEADERS}minstack 16
+            func $double 1 -> 1 {{ get 0 get 0 add ret }}
+            func $deep 1 -> 1 {{
+                get 0
+                get 0 inc
+                get 0 inc inc
+                get 0 dec
+                get 0 call $double
+                get 0 get 0 add
+                add add add add add
+                ret
+            }}
+            func $main {{ const 2 call $deep out %numb }}"
+        );
+        let args = Args {
+            max_regs: Some(2),
+            ..Args::default()
+        };
+        let limited = compile_with(&args, &source).unwrap();
+        let minreg = limited
+            .lines()
+            .find_map(|line| line.strip_prefix("MINREG "))
+            .unwrap();
+        assert!(minreg.parse::<usize>().unwrap() <= 2, "{limited}");
+        assert_eq!(emulate_urcl(&limited).unwrap(), "18");
+        assert_eq!(run_source(&source), "18");
+    }
+
+    #[test]
+    fn invalid_headers_are_errors() {
+        assert_error(
+            "bits 8\nbits 8\nminheap 16\nfunc $main { }",
+            "Duplicate header `bits`",
+        );
+        assert_error(
+            "bits 99999999999999999999999\nminheap 16\nfunc $main { }",
+            "Invalid value for header `bits`",
+        );
+    }
+
+    #[test]
+    fn unknown_labels_in_inst_bodies_are_errors() {
+        assert_error(
+            &format!(
+                "{HEADERS}
+                inst jumpy <&a> -> &out {{ MOV &out &a JMP :nowhere }}
+                func $main {{ const 1 jumpy out %numb }}"
+            ),
+            "Unknown label :nowhere",
+        );
+    }
+
+    #[test]
+    fn bits_ranges_are_emitted_as_is() {
+        let urcl =
+            compile_source("bits >= 16\nminheap 16\nfunc $main { const 300 out %numb }").unwrap();
+        assert!(urcl.contains("BITS >= 16\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "300");
+        let urcl =
+            compile_source("bits <= 8\nminheap 16\nfunc $main { const 200 out %numb }").unwrap();
+        assert!(urcl.contains("BITS <= 8\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "200");
+    }
+
+    #[test]
+    fn literals_must_fit_the_smallest_word_size() {
+        assert_error(
+            "bits >= 16\nminheap 16\nfunc $main { const 70000 out %numb }",
+            "This literal requires 17 bits, but the bits header is set to >= 16.",
+        );
+        // `<= 16` could still be 8 bits
+        assert_error(
+            "bits <= 16\nminheap 16\nfunc $main { const 300 out %numb }",
+            "This literal requires 9 bits, but the bits header is set to <= 16.",
+        );
+    }
+
+    #[test]
+    fn bits_128_goes_past_u64() {
+        let urcl = compile_source(
+            "bits 128
+            minheap 16
+            func $inc 1 -> 1 { get 0 inc ret }
+            func $main {
+                const 18446744073709551615 inc out %numb
+                const 18446744073709551616 call $inc out %numb
+                const 340282366920938463463374607431768211455 call $inc out %numb
+                const #18446744073709551616 out %numb
+            }",
+        )
+        .unwrap();
+        assert!(urcl.contains("#18446744073709551616"), "{urcl}");
+        assert_eq!(
+            emulate_urcl(&urcl).unwrap(),
+            concat!(
+                "18446744073709551616",
+                "18446744073709551617",
+                "0",
+                "18446744073709551616"
+            )
+        );
+    }
+}
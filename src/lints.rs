@@ -0,0 +1,103 @@
+use super::*;
+use clap::ArgEnum;
+use std::fmt::{self, Display, Formatter};
+
+/// Things that are probably mistakes, but not wrong enough to refuse to compile. They're all warnings by default.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lint {
+    /// A named label that no `jump` or `branch` goes to.
+    UnusedLabel,
+    /// A local (not an argument) that is never read or written with `get`, `set` or `ref`.
+    UnusedLocal,
+    /// Instructions after `ret`, `halt` or `jump` and a `height`, with no label before them to jump to.
+    UnreachableCode,
+    /// A func or inst in the main file that can't be reached from `$main` or `--keep`, so it's left out of the output.
+    UncalledFunc,
+    /// A data label in the main file that is never referenced, so it's left out of the output.
+    UnusedData,
+    /// `extern icall`, which trusts that the pointer really has that calling convention and signature.
+    ExternIcall,
+    /// Recursion, `icall` or an extern func from `$main`, so the declared `minstack` header can't be checked.
+    UnboundedStack,
+}
+
+impl Lint {
+    /// The name used on the command line, like `unused-label`.
+    pub fn name(self) -> &'static str {
+        self.to_possible_value()
+            .expect("No lints are skipped")
+            .get_name()
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Args {
+    /// How a lint should be reported, or `None` if it's allowed. `-D` beats `-W`, which beats `-A`.
+    pub fn lint_level(&self, lint: Lint) -> Option<Severity> {
+        if self.deny.contains(&lint) {
+            Some(Severity::Error)
+        } else if self.warn.contains(&lint) {
+            Some(Severity::Warning)
+        } else if self.allow.contains(&lint) {
+            None
+        } else {
+            Some(Severity::Warning)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    /// The severity of every `unused-label` in the program, after compiling it with `args`.
+    fn unused_labels(args: &Args) -> Vec<Severity> {
+        let prelude = CompilationUnit::prelude();
+        let source = source();
+        let unit = CompilationUnit::parse("test.ursl", &source);
+        let (_, errors) = compile(args, &[&prelude, &unit]);
+        errors
+            .iter()
+            .filter(|error| error.lint == Some(Lint::UnusedLabel))
+            .map(|error| error.severity)
+            .collect()
+    }
+
+    fn source() -> String {
+        format!("{HEADERS}func $main {{ label :unused const 1 out %numb }}")
+    }
+
+    #[test]
+    fn lints_are_warnings_by_default() {
+        assert_eq!(unused_labels(&Args::default()), [Severity::Warning]);
+        assert!(compile_source(&source()).is_ok());
+    }
+
+    #[test]
+    fn allowed_lints_are_hidden() {
+        let args = Args {
+            allow: vec![Lint::UnusedLabel],
+            ..Args::default()
+        };
+        assert_eq!(unused_labels(&args), []);
+    }
+
+    #[test]
+    fn denied_lints_fail_the_compile() {
+        let args = Args {
+            deny: vec![Lint::UnusedLabel],
+            ..Args::default()
+        };
+        assert_eq!(unused_labels(&args), [Severity::Error]);
+        assert_eq!(
+            compile_with(&args, &source()).unwrap_err(),
+            ["Label :unused is never jumped to"]
+        );
+    }
+}
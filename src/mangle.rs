@@ -1,3 +1,5 @@
+use std::iter;
+
 fn encode<'a>(fields: impl AsRef<[(&'a str, &'a str)]>) -> String {
     let fields = fields.as_ref();
     let mut result = String::with_capacity(
@@ -31,7 +33,44 @@ pub fn local_label(function: &str, label: &str) -> String {
     encode(&[("func", &function[1..]), ("label", label)])
 }
 
+/// A label for the target of a `~+n` or `~-n`, so the peephole pass can remove lines without moving it.
+pub fn relative_label(index: usize) -> String {
+    encode(&[("relative", index.to_string().as_str())])
+}
+
 pub fn function_name(function: &str) -> String {
     assert_eq!(function.chars().nth(0), Some('$'));
     encode(&[("func", &function[1..])])
 }
+
+/// Hexagn puts the types in the label, so that overloads don't collide. `*` in pointer types becomes `ptr`.
+///
+/// The name comes first, then the return type, then each param type, so `strlen(int8*) -> int` is `strlen_int_int8ptr`.
+/// This hasn't been checked against any particular Hexagn release, so a raw label is the way around a mismatch.
+pub fn hexagn_function_name(function: &str, params: &[&str], returns: &str) -> String {
+    assert_eq!(function.chars().next(), Some('$'));
+    let mut result = String::from(&function[1..]);
+    for ty in iter::once(returns).chain(params.iter().copied()) {
+        result.push('_');
+        result.push_str(&ty.replace('*', "ptr"));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexagn_labels_have_the_return_type_first() {
+        assert_eq!(
+            hexagn_function_name("$strlen", &["int8*"], "int"),
+            "strlen_int_int8ptr"
+        );
+        assert_eq!(
+            hexagn_function_name("$memset", &["int8*", "int8", "int"], "void"),
+            "memset_void_int8ptr_int8_int"
+        );
+        assert_eq!(hexagn_function_name("$exit", &[], "void"), "exit_void");
+    }
+}
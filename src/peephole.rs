@@ -0,0 +1,315 @@
+use super::*;
+use clap::ArgEnum;
+use std::fmt::{self, Display, Formatter};
+
+/// Patterns that the peephole pass cleans up. Each of them can be turned off with `--no-peephole`.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeepholeRule {
+    /// `MOV $1 $1` does nothing.
+    SelfMov,
+    /// `ADD SP SP 0` and `SUB SP SP 0` do nothing.
+    ZeroOffset,
+    /// `JMP .label` right before `.label` does nothing.
+    JumpToNext,
+    /// `PSH x` immediately followed by `POP y` is just `MOV y x`, or nothing if they're the same.
+    PushPop,
+}
+
+/// A single line of emitted URCL.
+#[derive(Clone)]
+pub enum Line {
+    Label(String),
+    Comment(String),
+    Instruction {
+        op: String,
+        operands: Vec<String>,
+    },
+    /// Anything that isn't safe to split on whitespace, like a `DW` or a char literal. Peephole rules never touch these.
+    Raw(String),
+}
+
+impl Line {
+    fn parse(line: &str) -> Self {
+        let trimmed = line.trim();
+        if trimmed.starts_with('.') {
+            Line::Label(trimmed.to_owned())
+        } else if trimmed.is_empty() || trimmed.starts_with("//") {
+            Line::Comment(line.to_owned())
+        } else if trimmed.contains(['\'', '"', '[']) || trimmed.starts_with("DW") {
+            Line::Raw(line.to_owned())
+        } else {
+            let mut tokens = trimmed.split_whitespace().map(str::to_owned);
+            Line::Instruction {
+                op: tokens.next().expect("Not empty, that's a comment"),
+                operands: tokens.collect(),
+            }
+        }
+    }
+
+    fn is_register(operand: &str) -> bool {
+        operand.starts_with('$') || operand == "SP"
+    }
+}
+
+impl Display for Line {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(label) => write!(f, "{label}"),
+            Line::Comment(text) | Line::Raw(text) => write!(f, "{text}"),
+            Line::Instruction { op, operands } => {
+                write!(f, "{op}")?;
+                for operand in operands {
+                    write!(f, " {operand}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Emitted URCL, kept in memory as a list of lines so it can be optimized before it's written out.
+#[derive(Clone, Default)]
+pub struct Program(pub Vec<Line>);
+
+impl Program {
+    pub fn parse(text: &str) -> Self {
+        Self(text.lines().map(Line::parse).collect())
+    }
+
+    /// Applies all the enabled rules until none of them change anything anymore.
+    pub fn optimize(&mut self, disabled: &[PeepholeRule]) {
+        self.resolve_relative();
+        let enabled = |rule| !disabled.contains(&rule);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let mut i = 0;
+            while i < self.0.len() {
+                let Line::Instruction { op, operands } = self.0[i].clone() else {
+                    i += 1;
+                    continue;
+                };
+                let ops = operands.iter().map(String::as_str).collect::<Vec<_>>();
+                let remove = match (op.as_str(), ops.as_slice()) {
+                    ("MOV", [dest, src]) => enabled(PeepholeRule::SelfMov) && dest == src,
+                    ("ADD" | "SUB", [dest, src, "0"]) => {
+                        enabled(PeepholeRule::ZeroOffset) && dest == src
+                    }
+                    ("JMP", [dest]) => {
+                        // skip over any labels and comments, since jumping to any of those labels is the same
+                        enabled(PeepholeRule::JumpToNext)
+                            && self.0[i + 1..]
+                                .iter()
+                                .take_while(|line| {
+                                    matches!(line, Line::Label(_) | Line::Comment(_))
+                                })
+                                .any(|line| matches!(line, Line::Label(label) if label == dest))
+                    }
+                    ("PSH", [src]) if enabled(PeepholeRule::PushPop) => {
+                        // comments are fine in between, but labels are not, since something else may jump there
+                        let next = self.0[i + 1..]
+                            .iter()
+                            .position(|line| !matches!(line, Line::Comment(_)))
+                            .map(|offset| i + 1 + offset);
+                        if let Some(next) = next {
+                            if let Line::Instruction { op, operands } = &self.0[next] {
+                                if let ("POP", [dest]) = (op.as_str(), operands.as_slice()) {
+                                    let merged = if dest == src {
+                                        None
+                                    } else if Line::is_register(src) {
+                                        Some(Line::Instruction {
+                                            op: "MOV".to_owned(),
+                                            operands: vec![dest.clone(), src.to_string()],
+                                        })
+                                    } else {
+                                        Some(Line::Instruction {
+                                            op: "IMM".to_owned(),
+                                            operands: vec![dest.clone(), src.to_string()],
+                                        })
+                                    };
+                                    match merged {
+                                        Some(merged) => self.0[next] = merged,
+                                        None => {
+                                            self.0.remove(next);
+                                        }
+                                    }
+                                    self.0.remove(i);
+                                    changed = true;
+                                    continue;
+                                }
+                            }
+                        }
+                        false
+                    }
+                    _ => false,
+                };
+                if remove {
+                    self.0.remove(i);
+                    changed = true;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Program {
+    /// Replaces `~+n` and `~-n` with labels, since they count instructions, and every rule removes some.
+    fn resolve_relative(&mut self) {
+        let instructions = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| matches!(line, Line::Instruction { .. } | Line::Raw(_)))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let len = self.0.len();
+        let mut targets = BTreeMap::<usize, String>::new();
+        for (n, &idx) in instructions.iter().enumerate() {
+            let Line::Instruction { operands, .. } = &mut self.0[idx] else {
+                continue;
+            };
+            for operand in operands {
+                let Some(offset) = operand
+                    .strip_prefix('~')
+                    .and_then(|offset| offset.parse::<isize>().ok())
+                else {
+                    continue;
+                };
+                // the end label of an inst at the very end points right past the last instruction
+                let target = match n.checked_add_signed(offset) {
+                    Some(target) if target < instructions.len() => instructions[target],
+                    Some(target) if target == instructions.len() => len,
+                    _ => continue,
+                };
+                let next = targets.len();
+                *operand = targets
+                    .entry(target)
+                    .or_insert_with(|| format!(".{}", mangle::relative_label(next)))
+                    .clone();
+            }
+        }
+        // from the back, so the indices of the rest stay the same
+        for (target, label) in targets.into_iter().rev() {
+            self.0.insert(target, Line::Label(label));
+        }
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for line in &self.0 {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn optimize(text: &str, disabled: &[PeepholeRule]) -> String {
+        let mut program = Program::parse(text);
+        program.optimize(disabled);
+        program.to_string()
+    }
+
+    #[test]
+    fn self_mov() {
+        assert_eq!(optimize("MOV $1 $1\nMOV $1 $2\n", &[]), "MOV $1 $2\n");
+    }
+
+    #[test]
+    fn zero_offset() {
+        assert_eq!(
+            optimize("ADD SP SP 0\nSUB SP SP 0\nADD SP SP 1\nADD $1 $2 0\n", &[]),
+            "ADD SP SP 1\nADD $1 $2 0\n"
+        );
+    }
+
+    #[test]
+    fn jump_to_next() {
+        assert_eq!(
+            optimize("JMP .b\n.a\n// comment\n.b\nHLT\n", &[]),
+            ".a\n// comment\n.b\nHLT\n"
+        );
+        let past_code = "JMP .a\nHLT\n.a\n";
+        assert_eq!(optimize(past_code, &[]), past_code);
+    }
+
+    #[test]
+    fn push_pop() {
+        assert_eq!(
+            optimize(
+                "PSH $1\n// comment\nPOP $2\nPSH 5\nPOP $3\nPSH $4\nPOP $4\n",
+                &[]
+            ),
+            "// comment\nMOV $2 $1\nIMM $3 5\n"
+        );
+        // something could jump to the label and pop a different value
+        let label = "PSH $1\n.label\nPOP $2\n";
+        assert_eq!(optimize(label, &[]), label);
+    }
+
+    #[test]
+    fn rules_combine_until_nothing_changes() {
+        assert_eq!(
+            optimize("PSH $1\nPOP $1\nJMP .next\n.next\n", &[]),
+            ".next\n"
+        );
+    }
+
+    #[test]
+    fn disabled_rules_are_left_alone() {
+        let text = "MOV $1 $1\nADD SP SP 0\nJMP .next\n.next\nPSH $1\nPOP $2\n";
+        let all = [
+            PeepholeRule::SelfMov,
+            PeepholeRule::ZeroOffset,
+            PeepholeRule::JumpToNext,
+            PeepholeRule::PushPop,
+        ];
+        assert_eq!(optimize(text, &all), text);
+        assert_eq!(
+            optimize(text, &[PeepholeRule::SelfMov]),
+            "MOV $1 $1\n.next\nMOV $2 $1\n"
+        );
+    }
+
+    #[test]
+    fn relative_branches_keep_their_target() {
+        let text = "BITS 8\nMINREG 2\nMINSTACK 1\nIMM $1 1\nBRZ ~+5 $0\nMOV $1 $1\nPSH $1\nPOP $2\nIMM $1 9\nOUT %NUMB $1\nHLT\n";
+        let optimized = optimize(text, &[]);
+        assert_eq!(
+            optimized,
+            "BITS 8\nMINREG 2\nMINSTACK 1\nIMM $1 1\nBRZ .URSL_relative_0 $0\nMOV $2 $1\nIMM $1 9\n.URSL_relative_0\nOUT %NUMB $1\nHLT\n"
+        );
+        assert_eq!(emulate_urcl(text).unwrap(), "1");
+        assert_eq!(emulate_urcl(&optimized).unwrap(), "1");
+    }
+
+    #[test]
+    fn relative_branches_in_inst_bodies() {
+        let source = format!(
+            "{HEADERS}
+            inst skip <&a> -> &out {{
+                MOV &out &a
+                JMP :done
+                MOV &out &out
+                PSH &out
+                POP &out
+                :done ADD &out &out 1
+            }}
+            func $main {{ const 5 skip out %numb }}"
+        );
+        assert_eq!(run_source(&source), "6");
+    }
+
+    #[test]
+    fn raw_lines_are_never_touched() {
+        let text = ".str\nDW [ \"MOV $1 $1\" ]\nIMM $1 'x'\n";
+        assert_eq!(optimize(text, &[]), text);
+    }
+}
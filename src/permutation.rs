@@ -4,7 +4,7 @@ use std::{
     hash::Hash,
 };
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Permutation {
     pub input: usize,
     pub output: Vec<usize>,
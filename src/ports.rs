@@ -0,0 +1,117 @@
+use num::{BigInt, BigUint, Num, One};
+use std::io::{self, BufRead, Write};
+
+/// Port I/O for running programs, on top of any reader and writer (usually stdin and stdout).
+///
+/// Only the text and number ports make sense for a terminal. `read` and `write` return `None` for anything else.
+pub struct Ports<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Ports<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    /// Reads a word from a port. At the end of the input, every port reads 0.
+    pub fn read(&mut self, port: &str, bits: u64) -> Option<io::Result<BigUint>> {
+        let modulus = BigUint::one() << bits;
+        Some(match port.to_ascii_uppercase().as_str() {
+            "TEXT" | "ASCII" | "ASCII8" => self
+                .read_char()
+                .map(|ch| BigUint::from(ch.map_or(0, u32::from)) % &modulus),
+            "NUMB" | "UINT" | "INT" => self.read_token().and_then(|token| {
+                let n = match token {
+                    Some(token) => BigInt::from_str_radix(&token, 10).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("%{port} expected a number, but got {token:?}: {err}"),
+                        )
+                    })?,
+                    None => BigInt::default(),
+                };
+                // negative numbers wrap around, same as everything else
+                let modulus = BigInt::from(modulus);
+                let n = ((n % &modulus) + &modulus) % &modulus;
+                Ok(n.to_biguint().expect("Just made it positive"))
+            }),
+            _ => return None,
+        })
+    }
+
+    pub fn write(&mut self, port: &str, value: &BigUint, bits: u64) -> Option<io::Result<()>> {
+        Some(match port.to_ascii_uppercase().as_str() {
+            "TEXT" | "ASCII" | "ASCII8" => {
+                let ch = u32::try_from(value)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                write!(self.output, "{ch}")
+            }
+            "NUMB" | "UINT" => write!(self.output, "{value}"),
+            "INT" => {
+                let n = if bits != 0 && value.bit(bits - 1) {
+                    BigInt::from(value.clone()) - BigInt::from(BigUint::one() << bits)
+                } else {
+                    BigInt::from(value.clone())
+                };
+                write!(self.output, "{n}")
+            }
+            "HEX" => write!(self.output, "{value:x}"),
+            "BIN" => write!(self.output, "{value:b}"),
+            _ => return None,
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.input.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.input.consume(1);
+        }
+        Ok(byte)
+    }
+
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        // make sure the prompt is visible before blocking on input
+        self.output.flush()?;
+        let Some(first) = self.read_byte()? else {
+            return Ok(None);
+        };
+        let len = match first.leading_ones() {
+            0 => 1,
+            n @ 2..=4 => n as usize,
+            _ => return Ok(Some(char::REPLACEMENT_CHARACTER)),
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            match self.read_byte()? {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        Ok(Some(
+            std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+                .unwrap_or(char::REPLACEMENT_CHARACTER),
+        ))
+    }
+
+    /// Reads the next whitespace separated token.
+    fn read_token(&mut self) -> io::Result<Option<String>> {
+        let mut token = String::new();
+        while let Some(ch) = self.read_char()? {
+            if !ch.is_whitespace() {
+                token.push(ch);
+            } else if !token.is_empty() {
+                break;
+            }
+        }
+        Ok(if token.is_empty() { None } else { Some(token) })
+    }
+}
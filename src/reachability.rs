@@ -0,0 +1,224 @@
+use super::*;
+use std::collections::HashSet;
+
+/// The functions and data labels that can actually be used at runtime. Everything else is left out of the output.
+pub struct Reachable<'a> {
+    pub functions: HashSet<&'a str>,
+    pub data: HashSet<&'a str>,
+}
+
+impl<'a> Reachable<'a> {
+    /// Walks everything reachable from `$main` and the functions given with `--keep`.
+    ///
+    /// With `--no-main` and nothing to keep, there's no root to start from, so everything is considered reachable.
+    pub fn find(args: &Args, result: &CompileResult<'a>) -> Self {
+        if args.no_main && args.keep.is_empty() {
+            return Self {
+                functions: result.functions.keys().copied().collect(),
+                data: result.defs.iter().map(|&(label, _)| label).collect(),
+            };
+        }
+
+        let defs = result
+            .defs
+            .iter()
+            .map(|(label, val)| (*label, val))
+            .collect::<HashMap<_, _>>();
+        let mut reachable = Self {
+            functions: HashSet::new(),
+            data: HashSet::new(),
+        };
+        let mut pending = Vec::new();
+        if !args.no_main {
+            pending.push(Literal::Func("$main"));
+        }
+        for name in args.keep.iter() {
+            // only names that exist can be reached, and unknown ones are already reported by compile
+            if let Some((&name, _)) = result.functions.get_key_value(name.as_str()) {
+                pending.push(Literal::Func(name));
+            }
+        }
+
+        // whatever calls these isn't URSL, so they can't be found by following calls
+        for func in result.functions.values() {
+            if let FunctionBody::Ursl {
+                export: Some(_), ..
+            } = func.body
+            {
+                pending.push(Literal::Func(func.name));
+            }
+        }
+
+        while let Some(lit) = pending.pop() {
+            match lit {
+                Literal::Func(name) => {
+                    if !reachable.functions.insert(name) {
+                        continue;
+                    }
+                    let Some(func) = result.functions.get(name) else {
+                        continue;
+                    };
+                    match &func.body {
+                        FunctionBody::Ursl { instructions, .. } => {
+                            for entry in instructions {
+                                match entry.instruction {
+                                    ursl::Instruction::Call(name)
+                                    | ursl::Instruction::TailCall(name)
+                                    | ursl::Instruction::Branch(name, _) => {
+                                        pending.push(Literal::Func(name))
+                                    }
+                                    ursl::Instruction::Const(ref lit) => pending.push(lit.clone()),
+                                    _ => (),
+                                }
+                            }
+                        }
+                        FunctionBody::Urcl {
+                            overloads, branch, ..
+                        } => {
+                            // any overload may be picked at any call site, so they all count
+                            let bodies = overloads
+                                .iter()
+                                .map(|overload| &overload.instructions)
+                                .chain(branch.iter().map(|branch| &branch.instructions));
+                            for instructions in bodies {
+                                for entry in instructions {
+                                    pending.extend(urcl_literals(&entry.instruction));
+                                }
+                            }
+                        }
+                        FunctionBody::Permutation(_)
+                        | FunctionBody::Extern(..)
+                        | FunctionBody::Deferred => (),
+                    }
+                }
+                Literal::Label(label) => {
+                    if !reachable.data.insert(label) {
+                        continue;
+                    }
+                    if let Some(val) = defs.get(label) {
+                        data_literals(val, &mut pending);
+                    }
+                }
+                _ => (),
+            }
+        }
+        reachable
+    }
+}
+
+/// The funcs whose address is taken anywhere, whether that's in code or data. These can be called with `icall`.
+pub fn func_pointers<'a>(result: &CompileResult<'a>) -> HashSet<&'a str> {
+    let mut literals = Vec::new();
+    for func in result.functions.values() {
+        match &func.body {
+            FunctionBody::Ursl { instructions, .. } => {
+                for entry in instructions {
+                    if let ursl::Instruction::Const(ref lit) = entry.instruction {
+                        literals.push(lit.clone());
+                    }
+                }
+            }
+            FunctionBody::Urcl {
+                overloads, branch, ..
+            } => {
+                let bodies = overloads
+                    .iter()
+                    .map(|overload| &overload.instructions)
+                    .chain(branch.iter().map(|branch| &branch.instructions));
+                for instructions in bodies {
+                    for entry in instructions {
+                        literals.extend(urcl_literals(&entry.instruction));
+                    }
+                }
+            }
+            FunctionBody::Permutation(_) | FunctionBody::Extern(..) | FunctionBody::Deferred => (),
+        }
+    }
+    for (_, val) in &result.defs {
+        data_literals(val, &mut literals);
+    }
+    literals
+        .into_iter()
+        .filter_map(|lit| match lit {
+            Literal::Func(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+fn urcl_literals<'a>(inst: &urcl::Instruction<'a>) -> Vec<Literal<'a>> {
+    match inst {
+        urcl::Instruction::Out {
+            source: urcl::Source::Literal(lit),
+            ..
+        } => vec![lit.clone()],
+        urcl::Instruction::Generic { sources, .. } => sources
+            .iter()
+            .filter_map(|source| match source {
+                urcl::Source::Literal(lit) => Some(lit.clone()),
+                urcl::Source::Register(_) => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn data_literals<'a>(val: &DataLiteral<'a>, out: &mut Vec<Literal<'a>>) {
+    match val {
+        DataLiteral::Literal(lit) => out.push(lit.clone()),
+        DataLiteral::Array(elements) => {
+            for (_, element) in elements {
+                data_literals(element, out);
+            }
+        }
+        DataLiteral::String(_) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn unused_funcs_and_data_are_left_out() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            .used 7
+            .unused 8
+            func $used 0 -> 0 {{ ret }}
+            func $unused 0 -> 0 {{ ret }}
+            func $main {{ call $used const .used load out %numb }}"
+        ))
+        .unwrap();
+        assert!(urcl.contains(".URSL_func_used\n"), "{urcl}");
+        assert!(!urcl.contains(".URSL_func_unused\n"), "{urcl}");
+        assert!(urcl.contains(".URSL_data_used\n"), "{urcl}");
+        assert!(!urcl.contains(".URSL_data_unused\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "7");
+    }
+
+    #[test]
+    fn kept_exported_and_data_funcs_are_emitted() {
+        let args = Args {
+            keep: vec!["$kept".to_owned()],
+            ..Args::default()
+        };
+        let urcl = compile_with(
+            &args,
+            &format!(
+                "{HEADERS}minstack 16
+                .table [ $viadata ]
+                func $kept 0 -> 0 {{ ret }}
+                export \"URCL++\" func $exported 0 -> 0 = .exported {{ ret }}
+                func $viadata 0 -> 1 {{ const 5 ret }}
+                func $main {{ const .table load icall 0 -> 1 out %numb }}"
+            ),
+        )
+        .unwrap();
+        for name in ["kept", "exported", "viadata"] {
+            assert!(urcl.contains(&format!(".URSL_func_{name}\n")), "{urcl}");
+        }
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "5");
+    }
+}
@@ -0,0 +1,91 @@
+//! Helpers for the tests in every module, which mostly start from URSL source.
+
+use super::*;
+
+/// The smallest headers a main file can have.
+pub const HEADERS: &str = "bits 8\nminheap 16\n";
+
+/// Compiles a program after the prelude. On failure, only the messages of the errors are returned, not warnings.
+pub fn compile_source(source: &str) -> Result<String, Vec<String>> {
+    compile_with(&Args::default(), source)
+}
+
+pub fn compile_with(args: &Args, source: &str) -> Result<String, Vec<String>> {
+    let prelude = CompilationUnit::prelude();
+    let unit = CompilationUnit::parse("test.ursl", source);
+    compile_to_string(args, &[&prelude, &unit]).map_err(|errors| {
+        errors
+            .into_iter()
+            .filter(SourceError::is_error)
+            .map(|error| error.message)
+            .collect()
+    })
+}
+
+/// Asserts that compiling fails with an error containing `message`.
+#[track_caller]
+pub fn assert_error(source: &str, message: &str) {
+    match compile_source(source) {
+        Ok(output) => {
+            panic!("Expected an error containing {message:?}, but it compiled to:\n{output}")
+        }
+        Err(errors) => assert!(
+            errors.iter().any(|error| error.contains(message)),
+            "Expected an error containing {message:?}, but got {errors:?}"
+        ),
+    }
+}
+
+/// Runs URCL in the emulator with no input, and returns what it wrote, or the error it stopped with.
+pub fn emulate_urcl(urcl: &str) -> Result<String, String> {
+    let program = emulator::Program::parse(urcl).map_err(|err| err.to_string())?;
+    let mut output = Vec::new();
+    emulator::emulate(&program, io::empty(), &mut output, Some(10_000_000))
+        .map_err(|err| err.to_string())?;
+    Ok(String::from_utf8(output).expect("Tests only write text"))
+}
+
+/// Compiles a program, runs it in the emulator, and returns what it wrote.
+#[track_caller]
+pub fn run_source(source: &str) -> String {
+    let urcl = compile_source(source)
+        .unwrap_or_else(|errors| panic!("Expected it to compile, but got {errors:?}"));
+    emulate_urcl(&urcl).unwrap_or_else(|err| panic!("{err} in:\n{urcl}"))
+}
+
+/// Compiles a program and runs it in the interpreter with no input, and returns what it wrote.
+#[track_caller]
+pub fn interpret_source(source: &str) -> String {
+    let prelude = CompilationUnit::prelude();
+    let unit = CompilationUnit::parse("test.ursl", source);
+    let (result, errors) = compile(&Args::default(), &[&prelude, &unit]);
+    let errors = errors
+        .into_iter()
+        .filter(SourceError::is_error)
+        .map(|error| error.message)
+        .collect::<Vec<_>>();
+    assert!(
+        errors.is_empty(),
+        "Expected it to compile, but got {errors:?}"
+    );
+    let mut output = Vec::new();
+    interpreter::run(&result, io::empty(), &mut output, Some(10_000_000))
+        .unwrap_or_else(|err| panic!("{}", err.message));
+    String::from_utf8(output).expect("Tests only write text")
+}
+
+/// The emitted URCL of one func, from its label up to the next func. Its own local labels are included.
+#[track_caller]
+pub fn func_body(urcl: &str, name: &str) -> String {
+    let label = format!(".{}", mangle::function_name(name));
+    let mut lines = urcl.lines().skip_while(|&line| line != label);
+    assert!(
+        lines.next().is_some(),
+        "{name} isn't in the output:\n{urcl}"
+    );
+    let next_func = |line: &str| line.starts_with(".URSL_func_") && !line.contains("_label_");
+    lines
+        .take_while(|&line| !next_func(line))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
@@ -77,16 +77,34 @@ impl Display for InputRegister<'_> {
 pub struct InputStackBindings<'a>(Vec<InputRegister<'a>>);
 pub struct OutputStackBindings<'a>(Vec<Register<'a>>);
 
-impl InputStackBindings<'_> {
+impl<'a> InputStackBindings<'a> {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The register each input is bound to, deepest first. Whether it's shared doesn't matter after allocation.
+    pub fn registers(&self) -> impl Iterator<Item = Register<'a>> + '_ {
+        self.0.iter().map(|&(InputRegister::Owned(reg) | InputRegister::Shared(reg))| reg)
+    }
 }
 
-impl OutputStackBindings<'_> {
+impl<'a> OutputStackBindings<'a> {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The register each output is read from, deepest first.
+    pub fn registers(&self) -> &[Register<'a>] {
+        &self.0
+    }
 }
 
 impl Display for InputStackBindings<'_> {
@@ -312,7 +330,7 @@ pub fn parse_instructions<'a>(
     let end = instructions.len() as isize;
     for i in 0..instructions.len() {
         let entry = instructions.get_mut(i).unwrap();
-        let lower = |dest| match dest {
+        let mut lower = |dest| match dest {
             BranchDestination::TemporaryLabel(label) => {
                 if let Some(label) = label {
                     if let Some(branch) = branch_destination {
@@ -323,7 +341,8 @@ pub fn parse_instructions<'a>(
                     if let Some(pos) = labels.get(label) {
                         BranchDestination::Relative((*pos as isize) - (i as isize))
                     } else {
-                        panic!("Unknown label :{label} at {}", entry.pos)
+                        errors.push(err!(@ Some(entry.pos.clone()), "Unknown label :{label}"));
+                        BranchDestination::Relative(0)
                     }
                 } else {
                     // end label
@@ -360,13 +379,16 @@ fn parse_label_ref<'a>(node: Node<'a>, unit: &'a CompilationUnit<'a>) -> Option<
 pub fn emit_instructions<'a>(
     f: &mut impl Write,
     instructions: &Vec<urcl::InstructionEntry<'a>>,
-    branch_target: Option<(&'a str, &'a str)>,
+    branch_target: Option<(&'a str, ursl::LocalLabel<'a>)>,
     reg_alloc: RegisterAllocation<'a>,
     InputStackBindings(input): &InputStackBindings<'a>,
     OutputStackBindings(output): &OutputStackBindings<'a>,
     max_regs: &mut usize,
 ) -> io::Result<RegisterAllocation<'a>> {
-    fn emit_dest(&dest: &BranchDestination, branch_target: Option<(&str, &str)>) -> String {
+    fn emit_dest(
+        &dest: &BranchDestination,
+        branch_target: Option<(&str, ursl::LocalLabel)>,
+    ) -> String {
         match dest {
             BranchDestination::TemporaryLabel(_) => {
                 unreachable!("Temporary label should have been lowered already.")
@@ -375,7 +397,7 @@ pub fn emit_instructions<'a>(
             BranchDestination::Relative(n) => format!("~+{n}"),
             BranchDestination::BranchLabel => match branch_target {
                 Some((func, label)) => {
-                    let mut label = mangle::local_label(func, label);
+                    let mut label = mangle::local_label(func, &label.to_string());
                     label.insert(0, '.');
                     label
                 }
@@ -584,6 +606,58 @@ pub fn __binary__<'a>(
     ]
 }
 
+/// The URCL for an [`ursl::Fusion`]. There's only one overload, since `LLOD` and `LSTR` don't write to their inputs anyway.
+pub fn fused_body<'a>(fusion: ursl::Fusion, pos: Position<'a>) -> UrclMainBody<'a> {
+    let reg = |name| Source::Register(Register::Named(name));
+    let inst = |op, dest, sources| InstructionEntry {
+        pos: pos.clone(),
+        instruction: Instruction::Generic {
+            op,
+            dest: Destination::Register(Register::Named(dest)),
+            sources,
+        },
+    };
+    let (input, output, instructions) = match fusion {
+        ursl::Fusion::Load => (
+            vec!["base", "offset"],
+            vec!["out"],
+            vec![inst("LLOD", "out", vec![reg("base"), reg("offset")])],
+        ),
+        ursl::Fusion::Store => (
+            vec!["base", "offset", "value"],
+            vec![],
+            vec![inst("LSTR", "base", vec![reg("offset"), reg("value")])],
+        ),
+        ursl::Fusion::CopyTo => (
+            vec!["base", "offset", "src"],
+            vec![],
+            vec![
+                inst("LOD", "tmp", vec![reg("src")]),
+                inst("LSTR", "base", vec![reg("offset"), reg("tmp")]),
+            ],
+        ),
+        ursl::Fusion::CopyFrom => (
+            vec!["dest", "base", "offset"],
+            vec![],
+            vec![
+                inst("LLOD", "tmp", vec![reg("base"), reg("offset")]),
+                inst("STR", "dest", vec![reg("tmp")]),
+            ],
+        ),
+    };
+    UrclMainBody {
+        input: InputStackBindings(
+            input
+                .into_iter()
+                .map(|name| InputRegister::Shared(Register::Named(name)))
+                .collect(),
+        ),
+        output: OutputStackBindings(output.into_iter().map(Register::Named).collect()),
+        instructions,
+        pos,
+    }
+}
+
 pub fn __branching__<'a>(
     node: Node<'a>,
     instruction: Node<'a>,
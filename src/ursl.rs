@@ -1,6 +1,7 @@
 use super::*;
-use std::{fmt::{self, Display, Formatter, Result}, cmp};
+use std::{fmt::{self, Display, Formatter, Result}, cmp, collections::HashSet};
 
+#[derive(Clone)]
 pub struct InstructionEntry<'a> {
     pub excess_height: usize,
     pub enter_height: usize,
@@ -16,6 +17,7 @@ impl PositionEntry for InstructionEntry<'_> {
     }
 }
 
+#[derive(Clone)]
 pub enum Instruction<'a> {
     Height(usize),
 
@@ -25,19 +27,69 @@ pub enum Instruction<'a> {
     In(&'a str),
     Out(&'a str),
 
-    Label(&'a str),
-    Jump(&'a str),
-    Branch(&'a str, &'a str),
+    Label(LocalLabel<'a>),
+    Jump(LocalLabel<'a>),
+    Branch(&'a str, LocalLabel<'a>),
 
     Halt,
 
     Call(&'a str),
-    IndirectCall(CallingConvention, StackBehaviour),
+    IndirectCall(CallingConvention<'a>, StackBehaviour),
     Ret,
+    /// Reuses the current frame for the callee's args, and jumps to it instead of calling it.
+    TailCall(&'a str),
+    TailIndirectCall(StackBehaviour),
 
     Ref(usize),
     Get(usize),
     Set(usize),
+
+    /// Never parsed, only created by [`fuse_addressing`] right before emitting.
+    Fused(Fusion),
+}
+
+/// Address arithmetic from the prelude that fits in a single `LLOD` or `LSTR`.
+#[derive(Clone, Copy)]
+pub enum Fusion {
+    /// `add load`
+    Load,
+    /// `add (value) store`
+    Store,
+    /// `add (src) copy`, copying to an offset
+    CopyTo,
+    /// `add copy`, copying from an offset
+    CopyFrom,
+}
+
+impl Display for Fusion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Load => write!(f, "add load"),
+            Self::Store => write!(f, "add (value) store"),
+            Self::CopyTo => write!(f, "add (src) copy"),
+            Self::CopyFrom => write!(f, "add copy"),
+        }
+    }
+}
+
+/// An instruction label within a function. Structured control flow generates its own labels, which can never collide with named ones.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LocalLabel<'a> {
+    Named(&'a str),
+    Structured(usize, &'static str),
+    /// A named label from an inlined func, renamed so it doesn't collide with the caller's.
+    Inlined(usize, &'a str),
+}
+
+impl Display for LocalLabel<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::Named(name) => write!(f, "{name}"),
+            // dots are not allowed in label names, so this is always unique
+            Self::Structured(id, kind) => write!(f, "{kind}.{id}"),
+            Self::Inlined(id, name) => write!(f, "inline.{id}.{name}"),
+        }
+    }
 }
 
 impl Display for Instruction<'_> {
@@ -61,21 +113,57 @@ impl Display for Instruction<'_> {
                 write!(f, "extern \"{call_convention}\" icall {stack}")
             }
             Self::Ret => write!(f, "ret"),
+            Self::TailCall(func) => write!(f, "tailcall {func}"),
+            Self::TailIndirectCall(stack) => write!(f, "tailicall {stack}"),
 
             Self::Ref(idx) => write!(f, "ret {idx}"),
             Self::Get(idx) => write!(f, "get {idx}"),
             Self::Set(idx) => write!(f, "set {idx}"),
+
+            Self::Fused(fusion) => write!(f, "{fusion}"),
         }
     }
 }
 
+#[derive(Clone, Copy)]
+enum Pending<'a> {
+    Node(Node<'a>),
+    Else,
+    End,
+}
+
+enum FrameKind<'a> {
+    Block(LocalLabel<'a>),
+    Loop,
+    If {
+        then: LocalLabel<'a>,
+        end: LocalLabel<'a>,
+    },
+}
+
+/// An open `block`, `loop` or `if` while parsing.
+struct Frame<'a> {
+    node: Node<'a>,
+    kind: FrameKind<'a>,
+    stack: StackBehaviour,
+    /// The stack height below the inputs of this frame, which is also the lowest height the body can pop to.
+    base: usize,
+    /// The floor of the enclosing frame, restored after this one ends.
+    outer_floor: usize,
+    /// The instruction index where this frame starts, for checking that jumps to its label are within it.
+    start: usize,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn parse_instructions<'a>(
     args: &Args,
     headers: &Headers,
     signatures: &HashMap<&str, (StackBehaviour, bool)>,
+    conventions: &HashMap<&'a str, CallingConvention<'a>>,
     parent: Node<'a>,
     nodes: Vec<Node<'a>>,
     func_name: &'a str,
+    params: usize,
     locals: usize,
     returns: usize,
     instructions: &mut Vec<InstructionEntry<'a>>,
@@ -83,8 +171,42 @@ pub fn parse_instructions<'a>(
 ) -> Vec<SourceError<'a>> {
     let mut errors = Vec::new();
     let mut height = Some(0usize);
-    let mut all_labels = HashMap::<&'a str, usize>::new();
-    for inst in nodes {
+    // set by a `height` after something that diverges, until the next label
+    let mut unreachable = false;
+    // inside a structured block, the stack can't be popped below its inputs
+    let mut floor = 0usize;
+    let mut all_labels = HashMap::<LocalLabel<'a>, usize>::new();
+    let mut scopes = HashMap::<LocalLabel<'a>, (usize, usize)>::new();
+    let mut frames = Vec::<Frame<'a>>::new();
+    let mut next_structured_label = 0usize;
+    // names from the func head, for the args and locals that have them
+    let head = parent.field("head", unit);
+    let mut names = HashMap::<&'a str, (usize, Node<'a>)>::new();
+    let param_names = head
+        .child_by_field_name("stack")
+        .and_then(|stack| stack.child_by_field_name("params"));
+    let local_names = head.child_by_field_name("locals");
+    for (list, first) in [(param_names, 0), (local_names, params)] {
+        let Some(list) = list.filter(|list| list.kind() == "names") else {
+            continue;
+        };
+        for (i, node) in list
+            .children_by_field_name("items", &mut unit.tree.walk())
+            .enumerate()
+        {
+            let name = node.text(unit);
+            if let Some((_, old)) = names.insert(name, (first + i, node)) {
+                err!(errors; unit; node, "Duplicate arg or local name {name}, previously used at {}", old.pos(unit));
+            }
+        }
+    }
+    // structured instructions push their bodies here, so it's processed as a stack in reverse order
+    let mut pending = nodes
+        .into_iter()
+        .rev()
+        .map(Pending::Node)
+        .collect::<Vec<_>>();
+    while let Some(next) = pending.pop() {
         // if args.verbose {
         //     println!();
         //     println!("-- parse so far for {func_name}");
@@ -97,15 +219,91 @@ pub fn parse_instructions<'a>(
         //         println!("height = ???");
         //     }
         // }
+        let inst = match next {
+            Pending::Node(inst) => inst,
+            Pending::Else | Pending::End => {
+                let frame = frames
+                    .last()
+                    .expect("Else and End are always pushed with a frame");
+                let expected = frame.base + frame.stack.output;
+                let node = frame.node;
+                if let Some(height) = height {
+                    if height != expected {
+                        err!(errors; unit; node, "Bad stack height at the end of {} (height here is {height}, but it should be {expected} after {})", node.kind(), frame.stack);
+                    }
+                }
+                let (label, after) = match (next, &frame.kind) {
+                    (Pending::Else, &FrameKind::If { then, end }) => {
+                        // fall out of the else body over the then body
+                        if height.is_some() {
+                            instructions.push(InstructionEntry {
+                                excess_height: expected,
+                                enter_height: expected,
+                                exit_height: None,
+                                instruction: Instruction::Jump(end),
+                                node,
+                                unit,
+                            });
+                            height = None;
+                        }
+                        (Some(then), frame.base + frame.stack.input)
+                    }
+                    (Pending::End, &FrameKind::Block(end) | &FrameKind::If { end, .. }) => {
+                        (Some(end), expected)
+                    }
+                    (Pending::End, FrameKind::Loop) => (None, expected),
+                    _ => unreachable!("Else is only pushed for if"),
+                };
+                if let Pending::End = next {
+                    let frame = frames.pop().unwrap();
+                    floor = frame.outer_floor;
+                    if let FrameKind::Block(end) | FrameKind::If { end, .. } = frame.kind {
+                        scopes.insert(end, (frame.start, instructions.len()));
+                    } else if let FrameKind::Loop = frame.kind {
+                        if let Some(Instruction::Label(start)) = instructions
+                            .get(frame.start)
+                            .map(|entry| &entry.instruction)
+                        {
+                            scopes.insert(*start, (frame.start, instructions.len()));
+                        }
+                    }
+                }
+                if let Some(label) = label {
+                    if height.is_none() {
+                        // nothing falls through, so the registers are not normalized and need to be reset
+                        instructions.push(InstructionEntry {
+                            excess_height: 0,
+                            enter_height: after,
+                            exit_height: Some(after),
+                            instruction: Instruction::Height(after),
+                            node,
+                            unit,
+                        });
+                    }
+                    all_labels.insert(label, instructions.len());
+                    unreachable = false;
+                    instructions.push(InstructionEntry {
+                        excess_height: after,
+                        enter_height: after,
+                        exit_height: Some(after),
+                        instruction: Instruction::Label(label),
+                        node,
+                        unit,
+                    });
+                    height = Some(after);
+                }
+                continue;
+            }
+        };
         macro_rules! op {
             () => {
                 inst.field("operand", unit)
             };
             (stack) => {
-                parse_stack(op!(), unit)
+                parse_stack(op!(), unit).extend_into(&mut errors)
             };
             (num) => {
-                parse_num(op!().text(unit))
+                parse_num(op!(), unit).extend_into(&mut errors)
             };
             (literal) => {{
                 let node = op!();
@@ -122,19 +320,26 @@ pub fn parse_instructions<'a>(
                 op!().text(unit)
             };
             (label) => {
-                op!().field("name", unit).text(unit)
+                LocalLabel::Named(op!().field("name", unit).text(unit))
             };
             (port) => {
                 op!().field("name", unit).text(unit)
             };
             (loc) => {{
-                let idx = op!(num);
-                assert!(
-                    idx < locals,
-                    "Out of bounds local variable at {}",
-                    inst.pos(unit)
-                );
-                idx
+                let node = op!();
+                if node.kind() == "number" {
+                    let idx: BigUint = op!(num);
+                    match usize::try_from(&idx).ok() {
+                        Some(idx) if idx < locals => idx,
+                        _ => err!(errors; unit; inst; 0, "Local {idx} is out of bounds, there are only {locals} args and locals here"),
+                    }
+                } else {
+                    let name = node.text(unit);
+                    match names.get(name) {
+                        Some(&(idx, _)) => idx,
+                        None => err!(errors; unit; node; 0, "Unknown arg or local {name} in {func_name}"),
+                    }
+                }
             }};
             (perm) => {
                 parse_permutation_sig(op!(), unit).extend_into(&mut errors)
@@ -144,7 +349,9 @@ pub fn parse_instructions<'a>(
         macro_rules! inst {
             ($height:ident => $e:expr) => {
                 {
-                    let $height = height.unwrap_or_else(|| panic!("Unknown stack height at {}", inst.pos(unit)));
+                    let $height = height.unwrap_or_else(|| {
+                        err!(errors; unit; inst; 0, "Unknown stack height here, since nothing falls through to it. Put a label or `height` before it")
+                    });
                     $e
                 }
             };
@@ -153,9 +360,9 @@ pub fn parse_instructions<'a>(
             ($inst:expr; $stack:expr) => {
                 inst!(enter_height => {
                     let excess_height = match enter_height.checked_sub($stack.input) {
-                        Some(height) => height,
-                        None => {
-                            err!(errors; unit; inst; 0, "Stack underflow")
+                        Some(height) if height >= floor => height,
+                        _ => {
+                            err!(errors; unit; inst; floor, "Stack underflow")
                         }
                     };
                     InstructionEntry {
@@ -169,6 +376,70 @@ pub fn parse_instructions<'a>(
                 })
             }
         }
+
+        // Takes the previous instruction as the condition of a `branch` or `if`
+        macro_rules! branch_prefix {
+            ($enter_height:expr) => {{
+                let enter_height = $enter_height;
+                let previous = instructions.pop().unwrap_or_else(|| {
+                    err!(errors; unit; inst; InstructionEntry {
+                        excess_height: enter_height,
+                        enter_height,
+                        exit_height: Some(enter_height),
+                        instruction: Instruction::Call(""),
+                        node: inst,
+                        unit,
+                    }, "Branch without a prefix instruction")
+                });
+                let opcode = if let Instruction::Call(opcode) = previous.instruction {
+                    opcode
+                } else {
+                    err!(errors; unit; inst; "", "Branch prefix has no branching variant")
+                };
+                // unwrap should only exist in the above error cases
+                // otherwise, it has already been proven to exist by the actual call implementation
+                let (stack, branching) = signatures.get(opcode).unwrap_or(&(stack!(0; -> 1), true));
+                if !branching {
+                    err!(errors; unit; inst, "Branch prefix has no branching variant");
+                }
+                assert_eq!(stack.output, 1);
+                (previous, opcode)
+            }};
+        }
+
+        // Opens a structured frame whose inputs are on top of the stack at `enter_height`
+        macro_rules! frame {
+            ($enter_height:expr, $kind:expr) => {{
+                let enter_height: usize = $enter_height;
+                let stack = parse_stack_sig(inst, unit).extend_into(&mut errors);
+                let base = match enter_height.checked_sub(stack.input) {
+                    Some(base) if base >= floor => base,
+                    _ => err!(errors; unit; inst; floor, "Stack underflow"),
+                };
+                frames.push(Frame {
+                    node: inst,
+                    kind: $kind,
+                    stack,
+                    base,
+                    outer_floor: floor,
+                    start: instructions.len(),
+                });
+                floor = base;
+                stack
+            }};
+        }
+
+        macro_rules! structured_label {
+            ($kind:literal) => {{
+                let label = inst
+                    .child_by_field_name("label")
+                    .map(|label| LocalLabel::Named(label.field("name", unit).text(unit)))
+                    .unwrap_or(LocalLabel::Structured(next_structured_label, $kind));
+                next_structured_label += 1;
+                label
+            }};
+        }
+
         let entry = match inst.kind() {
             "height" => {
                 let operand = op!(num);
@@ -214,10 +485,25 @@ pub fn parse_instructions<'a>(
             "extern_icall" => {
                 let stack = op!(stack);
                 let call_convention =
-                    parse_call_convention(inst.field("call_convention", unit), unit)
+                    parse_call_convention(inst.field("call_convention", unit), conventions, unit)
                         .extend_into(&mut errors);
+                lint!(errors; args; lints::Lint::ExternIcall; unit; inst, "extern \"{call_convention}\" icall can't check that the func pointer really is {call_convention} with ({stack})");
                 inst!(Instruction::IndirectCall(call_convention, stack); stack!(stack.input + 1; -> stack.output))
             }
+            "tailcall" => {
+                let operand = op!(func);
+                let stack = match signatures.get(operand) {
+                    Some((stack, _)) => *stack,
+                    None => {
+                        err!(errors; unit; inst; stack!(0; -> 0), "Tail call to unknown func {operand}")
+                    }
+                };
+                inst!(enter_height => tail_call(Instruction::TailCall(operand), stack, enter_height, params, returns, inst, unit).extend_into(&mut errors))
+            }
+            "tailicall" => {
+                let stack = op!(stack);
+                inst!(enter_height => tail_call(Instruction::TailIndirectCall(stack), stack, enter_height, params, returns, inst, unit).extend_into(&mut errors))
+            }
             "ref" => inst!(Instruction::Ref(op!(loc)); 0 -> 1),
             "get" => inst!(Instruction::Get(op!(loc)); 0 -> 1),
             "set" => inst!(Instruction::Set(op!(loc)); 1 -> 0),
@@ -238,28 +524,7 @@ pub fn parse_instructions<'a>(
                 unit,
             }),
             "branch" => inst! { enter_height => {
-                let previous = instructions.pop().unwrap_or_else(|| {
-                    err!(errors; unit; inst; InstructionEntry {
-                        excess_height: enter_height,
-                        enter_height,
-                        exit_height: Some(enter_height),
-                        instruction: Instruction::Call(""),
-                        node: inst,
-                        unit,
-                    }, "Branch without a prefix instruction")
-                });
-                let opcode = if let Instruction::Call(opcode) = previous.instruction {
-                    opcode
-                } else {
-                    err!(errors; unit; inst; "", "Branch prefix has no branching variant")
-                };
-                // unwrap should only exist in the above error cases
-                // otherwise, it has already been proven to exist by the actual call implementation
-                let (stack, branching) = signatures.get(opcode).unwrap_or(&(stack!(0; -> 1), true));
-                if !branching {
-                    err!(errors; unit; inst, "Branch prefix has no branching variant");
-                }
-                assert_eq!(stack.output, 1);
+                let (previous, opcode) = branch_prefix!(enter_height);
                 InstructionEntry {
                     enter_height: previous.enter_height,
                     excess_height: previous.excess_height,
@@ -269,6 +534,81 @@ pub fn parse_instructions<'a>(
                     unit,
                 }
             }},
+            "block" => inst!(enter_height => {
+                let end = structured_label!("block");
+                frame!(enter_height, FrameKind::Block(end));
+                pending.push(Pending::End);
+                pending.extend(
+                    inst.children_by_field_name("instruction", &mut unit.tree.walk())
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .rev()
+                        .map(Pending::Node),
+                );
+                continue;
+            }),
+            "loop" => inst!(enter_height => {
+                let start = structured_label!("loop");
+                frame!(enter_height, FrameKind::Loop);
+                pending.push(Pending::End);
+                pending.extend(
+                    inst.children_by_field_name("instruction", &mut unit.tree.walk())
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .rev()
+                        .map(Pending::Node),
+                );
+                all_labels.insert(start, instructions.len());
+                InstructionEntry {
+                    excess_height: enter_height,
+                    enter_height,
+                    exit_height: Some(enter_height),
+                    instruction: Instruction::Label(start),
+                    node: inst,
+                    unit,
+                }
+            }),
+            "if" => inst!(enter_height => {
+                let (previous, opcode) = branch_prefix!(enter_height);
+                let then = LocalLabel::Structured(next_structured_label, "then");
+                next_structured_label += 1;
+                // like a block, jumping to the label of an if leaves it
+                let end = structured_label!("end");
+                let stack = frame!(previous.excess_height, FrameKind::If { then, end });
+                let else_body = inst.child_by_field_name("else");
+                if else_body.is_none() && stack.input != stack.output {
+                    err!(errors; unit; inst, "if without else must leave the stack as it was, but it has ({stack})");
+                }
+                // the condition jumps over the else body to the then body
+                pending.push(Pending::End);
+                pending.extend(
+                    inst.field("then", unit)
+                        .children_by_field_name("instruction", &mut unit.tree.walk())
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .rev()
+                        .map(Pending::Node),
+                );
+                pending.push(Pending::Else);
+                if let Some(else_body) = else_body {
+                    pending.extend(
+                        else_body
+                            .children_by_field_name("instruction", &mut unit.tree.walk())
+                            .collect::<Vec<_>>()
+                            .into_iter()
+                            .rev()
+                            .map(Pending::Node),
+                    );
+                }
+                InstructionEntry {
+                    enter_height: previous.enter_height,
+                    excess_height: previous.excess_height,
+                    exit_height: Some(previous.excess_height),
+                    instruction: Instruction::Branch(opcode, then),
+                    node: inst,
+                    unit,
+                }
+            }),
             "ret" => inst!(enter_height => {
                 if enter_height != returns {
                     err!(errors; unit; inst, "Bad stack height (height here is {enter_height}, but function returns {returns})");
@@ -292,28 +632,78 @@ pub fn parse_instructions<'a>(
             }),
             "custom_instruction" => {
                 let opcode = inst.field("opcode", unit).text(unit);
-                let (stack, _) = signatures
-                    .get(opcode)
-                    .unwrap_or_else(|| panic!("Unknown instruction {}", opcode));
+                let stack = match signatures.get(opcode) {
+                    Some((stack, _)) => *stack,
+                    None => {
+                        err!(errors; unit; inst; stack!(0; -> 0), "Unknown instruction {opcode}")
+                    }
+                };
                 inst!(Instruction::Call(opcode); stack)
             }
             _ => unknown_node(inst, unit),
         };
+        match entry.instruction {
+            Instruction::Label(_) => unreachable = false,
+            Instruction::Height(_) => unreachable = height.is_none(),
+            _ if unreachable => {
+                // only the first one, the rest of them are obviously also unreachable
+                lint!(errors; args; lints::Lint::UnreachableCode; unit; inst, "Unreachable code. Nothing falls through to it, and there is no label before it to jump to");
+                unreachable = false;
+            }
+            _ => (),
+        }
         height = entry.exit_height;
         instructions.push(entry);
     }
-    for entry in instructions.iter() {
+    for (i, entry) in instructions.iter().enumerate() {
         if let Instruction::Jump(label) | Instruction::Branch(_, label) = entry.instruction {
-            if let Some(&idx) = all_labels.get(label) {
+            if let Some(&idx) = all_labels.get(&label) {
                 let dest = &instructions[idx];
                 if entry.excess_height != dest.enter_height {
                     err!(errors; unit; entry.node, "Incorrect stack height on branch (height here is {} but destination expects {})", entry.excess_height, dest.enter_height);
                 }
+                if let Some(&(start, end)) = scopes.get(&label) {
+                    if !(start..end).contains(&i) {
+                        err!(errors; unit; entry.node, "Branch or jump to :{label} from outside of its {}", dest.node.kind());
+                    }
+                }
             } else {
                 err!(errors; unit; entry.node, "Branch or jump to unknown label {func_name}:{label}")
             }
         }
     }
+    let mut used_labels = HashSet::new();
+    let mut used_locals = HashSet::new();
+    for entry in instructions.iter() {
+        match entry.instruction {
+            Instruction::Jump(label) | Instruction::Branch(_, label) => {
+                used_labels.insert(label);
+            }
+            Instruction::Ref(idx) | Instruction::Get(idx) | Instruction::Set(idx) => {
+                used_locals.insert(idx);
+            }
+            _ => (),
+        }
+    }
+    for entry in instructions.iter() {
+        if let Instruction::Label(label @ LocalLabel::Named(_)) = entry.instruction {
+            if !used_labels.contains(&label) {
+                // a named block or loop points at its label, instead of the whole thing
+                let node = entry.node.child_by_field_name("label").unwrap_or(entry.node);
+                lint!(errors; args; lints::Lint::UnusedLabel; unit; node, "Label :{label} is never jumped to");
+            }
+        }
+    }
+    for idx in params..locals {
+        if !used_locals.contains(&idx) {
+            // point at the name if it has one, or at the number of locals otherwise
+            let node = names
+                .values()
+                .find(|&&(named, _)| named == idx)
+                .map_or_else(|| head.field("locals", unit), |&(_, node)| node);
+            lint!(errors; args; lints::Lint::UnusedLocal; unit; node, "Local {idx} is never used");
+        }
+    }
     if returns == 0 {
         if let Some(height) = height {
             if height != 0 {
@@ -335,19 +725,72 @@ pub fn parse_instructions<'a>(
     errors
 }
 
+/// Checks that a tail call can reuse the current frame. The callee gets this func's arg slots, so it can't take any
+/// more args than that, and whatever it returns is returned from here directly.
+fn tail_call<'a>(
+    instruction: Instruction<'a>,
+    stack: StackBehaviour,
+    enter_height: usize,
+    params: usize,
+    returns: usize,
+    inst: Node<'a>,
+    unit: &'a CompilationUnit<'a>,
+) -> (InstructionEntry<'a>, Vec<SourceError<'a>>) {
+    let mut errors = Vec::new();
+    // the func pointer is below the args
+    let inputs = stack.input + matches!(instruction, Instruction::TailIndirectCall(_)) as usize;
+    if enter_height != inputs {
+        err!(errors; unit; inst, "Bad stack height (height here is {enter_height}, but {instruction} takes {inputs})");
+    }
+    if stack.output != returns {
+        err!(errors; unit; inst, "{instruction} returns {}, but function returns {returns}", stack.output);
+    }
+    if stack.input > params {
+        err!(errors; unit; inst, "{instruction} takes {} args, but there are only {params} arg slots here to put them in", stack.input);
+    }
+    (
+        InstructionEntry {
+            excess_height: 0,
+            enter_height,
+            exit_height: None,
+            instruction,
+            node: inst,
+            unit,
+        },
+        errors,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn emit_instructions<'a>(
     args: &Args,
+    headers: &Headers,
     f: &mut impl Write,
     functions: &BTreeMap<&'a str, Function<'a>>,
     func: &Function<'a>,
     locals: usize,
     instructions: &Vec<InstructionEntry<'a>>,
+    register_args: &HashMap<&'a str, usize>,
     max_regs: &mut usize,
-) -> io::Result<()> {
+) -> io::Result<Vec<SourceError<'a>>> {
     assert!(!instructions.is_empty()); // empty instruction lists are only allowed for -> 0, and parsing normalizes them to end with a ret
     writeln!(f, ".{}", mangle::function_name(func.name))?;
+    // URSL code still calls it by the mangled label, so the exported one is just another name for the same place
+    let call_convention = match &func.body {
+        FunctionBody::Ursl {
+            export: Some((call_convention, label)),
+            ..
+        } => {
+            if *label != mangle::function_name(func.name) {
+                writeln!(f, ".{label}")?;
+            }
+            *call_convention
+        }
+        _ => CallingConvention::URSL,
+    };
     if args.garbage_initialized_locals {
-        if func.stack.input != 0 {
+        // inlining can give locals to funcs without any args, like $main
+        if locals != 0 {
             writeln!(f, "SUB SP SP {locals}")?;
         }
     } else {
@@ -355,6 +798,9 @@ pub fn emit_instructions<'a>(
             writeln!(f, "PSH 0")?;
         }
     }
+    // the first args are in $1 and up for the whole func, and the rest are on the callstack like usual
+    let in_regs = register_args.get(func.name).copied().unwrap_or(0);
+    *max_regs = cmp::max(*max_regs, in_regs);
     let map_loc = |idx| {
         // this is really just (idx - func.stack.input) % (func.stack.input + locals)
         // but i think this is cleaner?
@@ -365,14 +811,42 @@ pub fn emit_instructions<'a>(
         // which is why this closure exists
         if idx < func.stack.input {
             // idx is referring to an arg, skip the ret pointer!
-            idx + locals + 1
+            idx - in_regs + locals + 1
         } else {
             // idx is referring to a local
             idx - func.stack.input
         }
     };
-    let mut reg_alloc = RegisterAllocation::new();
-    Ok(for entry in instructions {
+    let instructions = &fuse_addressing(functions, instructions);
+    let live = live_slots_after(functions, instructions);
+    let limit = args.max_regs.map(|max| {
+        // branches are normalized with their inputs on top of the normal allocation, so they need to fit too
+        let branch_inputs = instructions
+            .iter()
+            .filter_map(|entry| match entry.instruction {
+                Instruction::Branch(prefix, _) => match functions.get(prefix) {
+                    Some(Function {
+                        body:
+                            FunctionBody::Urcl {
+                                branch: Some(branch),
+                                ..
+                            },
+                        ..
+                    }) => Some(branch.input.len()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        RegisterLimit {
+            max,
+            at_labels: max.saturating_sub(branch_inputs),
+        }
+    });
+    let mut errors = Vec::new();
+    let mut reg_alloc = RegisterAllocation::new(limit, in_regs);
+    for (entry, live) in instructions.iter().zip(live.iter()) {
         if args.verbose {
             writeln!(f)?;
             writeln!(f, "// stack:{reg_alloc:?}")?;
@@ -387,37 +861,173 @@ pub fn emit_instructions<'a>(
             println!("reg alloc:{reg_alloc:?}");
             println!("emitting: {}", entry.instruction);
         }
+        macro_rules! too_many_regs {
+            ($count:expr) => {
+                err!(errors; entry.unit; entry.node, "This needs {} registers, but --max-regs is {}", $count, args.max_regs.unwrap_or_default())
+            };
+        }
+        macro_rules! make_room {
+            () => {
+                if !reg_alloc.make_room(f, 1, reg_alloc.height())? {
+                    too_many_regs!(1)
+                }
+            };
+        }
+        // picks the best overload, spilling more of the stack until one fits if there's a register limit
+        macro_rules! emit_overloads {
+            ($overloads:expr) => {{
+                let (new_reg_alloc, emitted, new_max_regs) = loop {
+                    let mut candidates = $overloads
+                        .iter()
+                        .map(
+                            |UrclMainBody {
+                                 input,
+                                 output,
+                                 instructions,
+                                 pos: _,
+                             }| {
+                                let mut emit = Vec::new();
+                                let mut max_regs = 0;
+                                let reg_alloc = urcl::emit_instructions(
+                                    &mut emit,
+                                    instructions,
+                                    None,
+                                    reg_alloc.clone(),
+                                    input,
+                                    output,
+                                    &mut max_regs,
+                                )
+                                .unwrap();
+                                let emit = String::from_utf8(emit).unwrap();
+                                (reg_alloc, emit, max_regs)
+                            },
+                        )
+                        .collect::<Vec<_>>();
+                    candidates.sort_by(|(_, a_emit, a_max_regs), (_, b_emit, b_max_regs)| {
+                        a_emit
+                            .lines()
+                            .count()
+                            .cmp(&b_emit.lines().count())
+                            .then(a_max_regs.cmp(b_max_regs))
+                    });
+                    assert!(
+                        !candidates.is_empty(),
+                        "there should be at least one non-branching overload"
+                    );
+                    // with a register limit, spill more of the stack until some overload fits
+                    if let Some(fits) = candidates
+                        .iter()
+                        .position(|(_, _, used)| limit.is_none_or(|limit| *used <= limit.max))
+                    {
+                        break candidates.swap_remove(fits);
+                    }
+                    if !reg_alloc.spill_one(f, entry.excess_height)? {
+                        too_many_regs!(candidates[0].2);
+                        break candidates.swap_remove(0);
+                    }
+                };
+                reg_alloc = new_reg_alloc;
+                write!(f, "{emitted}")?;
+                *max_regs = (*max_regs).max(new_max_regs);
+            }};
+        }
+        if let Some(limit) = limit {
+            if !matches!(entry.instruction, Instruction::Height(_)) {
+                // the inputs of every instruction have to be in registers
+                let inputs = entry.enter_height - entry.excess_height;
+                if inputs > limit.max {
+                    too_many_regs!(inputs);
+                }
+                let spilled_inputs = reg_alloc.spilled().saturating_sub(entry.excess_height);
+                reg_alloc.make_room(f, spilled_inputs, entry.excess_height)?;
+                reg_alloc.reload_top(f, inputs, max_regs)?;
+            }
+        }
         match entry.instruction {
             Instruction::Height(height) => {
-                reg_alloc = RegisterAllocation::normal(height);
+                reg_alloc = RegisterAllocation::normal(height, limit, in_regs);
             }
             Instruction::Ret => {
-                reg_alloc.normalize(args, f, max_regs, 0)?;
+                if limit.is_some_and(|limit| func.stack.output > limit.max) {
+                    too_many_regs!(func.stack.output);
+                }
+                // return values are always in registers, so nothing can stay spilled
+                reg_alloc.reload_top(f, reg_alloc.height(), max_regs)?;
+                reg_alloc.without_limit().normalize(args, f, max_regs, 0)?;
+                if call_convention.returns != 1 {
+                    // backwards, so that it doesn't overwrite any it hasn't moved yet
+                    for reg in (1..=func.stack.output).rev() {
+                        let dest = reg + call_convention.returns - 1;
+                        *max_regs = cmp::max(*max_regs, dest);
+                        writeln!(f, "MOV ${dest} ${reg}")?;
+                    }
+                }
                 if locals != 0 {
                     writeln!(f, "ADD SP SP {locals}")?;
                 }
                 writeln!(f, "RET")?;
             }
+            Instruction::TailCall(_) | Instruction::TailIndirectCall(_) => {
+                let (dest, stack) = match entry.instruction {
+                    Instruction::TailCall(callee) => (
+                        AllocationSlot::Literal(Literal::Func(callee)),
+                        functions[callee].stack,
+                    ),
+                    Instruction::TailIndirectCall(stack) => {
+                        (reg_alloc.get(stack.input + 1)[0].clone(), stack)
+                    }
+                    _ => unreachable!(),
+                };
+                // the callee's args go where this func's args were, and the caller cleans up all of them like usual
+                let params = reg_alloc.get(stack.input).to_vec();
+                for (idx, param) in params.into_iter().enumerate() {
+                    writeln!(f, "LSTR SP {} {param}", map_loc(idx) + reg_alloc.spilled())?;
+                }
+                if locals != 0 {
+                    writeln!(f, "ADD SP SP {locals}")?;
+                }
+                writeln!(f, "JMP {dest}")?;
+            }
             Instruction::Halt => writeln!(f, "HLT")?,
             Instruction::Const(ref lit) => reg_alloc.push(AllocationSlot::Literal(lit.clone())),
+            Instruction::Get(idx) if idx < in_regs => {
+                make_room!();
+                writeln!(f, "MOV {} ${}", reg_alloc.apply_next_reg(), idx + 1)?
+            }
+            Instruction::Set(idx) if idx < in_regs => match reg_alloc.apply_pop1() {
+                reg @ AllocationSlot::Register(_) => writeln!(f, "MOV ${} {reg}", idx + 1)?,
+                lit => writeln!(f, "IMM ${} {lit}", idx + 1)?,
+            },
             Instruction::Ref(idx) => {
-                writeln!(f, "ADD {} SP {}", reg_alloc.apply_next_reg(), map_loc(idx))?
+                make_room!();
+                let offset = map_loc(idx) + reg_alloc.spilled();
+                writeln!(f, "ADD {} SP {offset}", reg_alloc.apply_next_reg())?
             }
             Instruction::Get(idx) => {
-                writeln!(f, "LLOD {} SP {}", reg_alloc.apply_next_reg(), map_loc(idx))?
+                make_room!();
+                let offset = map_loc(idx) + reg_alloc.spilled();
+                writeln!(f, "LLOD {} SP {offset}", reg_alloc.apply_next_reg())?
             }
             Instruction::Set(idx) => {
-                writeln!(f, "LSTR SP {} {}", map_loc(idx), reg_alloc.apply_pop1())?
+                let offset = map_loc(idx) + reg_alloc.spilled();
+                writeln!(f, "LSTR SP {offset} {}", reg_alloc.apply_pop1())?
+            }
+            Instruction::In(port) => {
+                make_room!();
+                writeln!(f, "IN {} %{port}", reg_alloc.apply_next_reg())?
             }
-            Instruction::In(port) => writeln!(f, "IN {} %{port}", reg_alloc.apply_next_reg())?,
             Instruction::Out(port) => writeln!(f, "OUT %{port} {}", reg_alloc.apply_pop1())?,
             Instruction::Label(label) => {
                 reg_alloc.normalize(args, f, max_regs, 0)?;
-                writeln!(f, ".{}", mangle::local_label(func.name, label))?
+                writeln!(f, ".{}", mangle::local_label(func.name, &label.to_string()))?
             }
             Instruction::Jump(label) => {
                 reg_alloc.normalize(args, f, max_regs, 0)?;
-                writeln!(f, "JMP .{}", mangle::local_label(func.name, label))?
+                writeln!(
+                    f,
+                    "JMP .{}",
+                    mangle::local_label(func.name, &label.to_string())
+                )?
             }
             Instruction::Branch(prefix, label) => {
                 if let Some(Function {
@@ -430,68 +1040,62 @@ pub fn emit_instructions<'a>(
                                     instructions,
                                     pos: _,
                                 }),
+                            fold: _,
                         },
                     ..
                 }) = functions.get(prefix)
                 {
                     reg_alloc.normalize(args, f, max_regs, input.len())?;
+                    let mut emit = Vec::new();
+                    let mut used_regs = 0;
                     reg_alloc = urcl::emit_instructions(
-                        f,
+                        &mut emit,
                         instructions,
                         Some((func.name, label)),
                         reg_alloc,
                         input,
                         &Default::default(),
-                        max_regs,
+                        &mut used_regs,
                     )?;
+                    if limit.is_some_and(|limit| used_regs > limit.max) {
+                        too_many_regs!(used_regs);
+                    }
+                    *max_regs = (*max_regs).max(used_regs);
+                    f.write_all(&emit)?;
                 } else {
                     unreachable!("Already checked that func exists.")
                 }
             }
             Instruction::Perm(ref perm) => reg_alloc.apply_permutation(perm),
+            Instruction::Fused(fusion) => {
+                emit_overloads!([urcl::fused_body(fusion, entry.pos())]);
+            }
             Instruction::Call(func) => {
                 if let Some(func) = functions.get(func) {
                     match &func.body {
                         FunctionBody::Urcl {
                             overloads,
                             branch: _,
+                            fold: fold_op,
                         } => {
-                            let (new_reg_alloc, emitted, new_max_regs) = overloads
-                                .iter()
-                                .map(
-                                    |UrclMainBody {
-                                         input,
-                                         output,
-                                         instructions,
-                                         pos: _,
-                                     }| {
-                                        let mut emit = Vec::new();
-                                        let mut max_regs = 0;
-                                        let reg_alloc = urcl::emit_instructions(
-                                            &mut emit,
-                                            instructions,
-                                            None,
-                                            reg_alloc.clone(),
-                                            input,
-                                            output,
-                                            &mut max_regs,
-                                        )
-                                        .unwrap();
-                                        let emit = String::from_utf8(emit).unwrap();
-                                        (reg_alloc, emit, max_regs)
-                                    },
-                                )
-                                .min_by(|(_, a_emit, a_max_regs), (_, b_emit, b_max_regs)| {
-                                    a_emit
-                                        .lines()
-                                        .count()
-                                        .cmp(&b_emit.lines().count())
-                                        .then(a_max_regs.cmp(b_max_regs))
-                                })
-                                .expect("there should be at least one non-branching overload");
-                            reg_alloc = new_reg_alloc;
-                            write!(f, "{emitted}")?;
-                            *max_regs = new_max_regs;
+                            // const 2 const 3 add is just const 5
+                            let folded = fold_op.and_then(|op| {
+                                let inputs = reg_alloc
+                                    .get(func.stack.input)
+                                    .iter()
+                                    .map(|slot| match slot {
+                                        AllocationSlot::Literal(Literal::Num(n)) => Some(n.clone()),
+                                        _ => None,
+                                    })
+                                    .collect::<Option<Vec<_>>>()?;
+                                fold::evaluate(op, &inputs, headers.bits.exact()?)
+                            });
+                            if let Some(folded) = folded {
+                                reg_alloc.pop(func.stack.input);
+                                reg_alloc.push(AllocationSlot::Literal(Literal::Num(folded)));
+                                continue;
+                            }
+                            emit_overloads!(overloads);
                         }
                         FunctionBody::Permutation(perm) => reg_alloc.apply_permutation(perm),
 
@@ -499,6 +1103,16 @@ pub fn emit_instructions<'a>(
                         // Emit it as URSL call convention because that is the primary use for it
                         // and that's a reasonable default in general too.
                         FunctionBody::Deferred | FunctionBody::Ursl { .. } => {
+                            let call_convention = match &func.body {
+                                FunctionBody::Ursl {
+                                    export: Some((call_convention, _)),
+                                    ..
+                                } => *call_convention,
+                                _ => CallingConvention::URSL,
+                            };
+                            if limit.is_some_and(|limit| func.stack.output > limit.max) {
+                                too_many_regs!(func.stack.output);
+                            }
                             let params = reg_alloc.get(func.stack.input).to_vec();
                             reg_alloc.pop(params.len());
                             write_call(
@@ -507,12 +1121,17 @@ pub fn emit_instructions<'a>(
                                 CallDest::Slot(AllocationSlot::Literal(Literal::Func(func.name))),
                                 func.stack,
                                 params,
+                                register_args.get(func.name).copied().unwrap_or(0),
                                 &mut reg_alloc,
-                                CallingConvention::URSL,
+                                live,
+                                call_convention,
                                 max_regs,
                             )?;
                         }
                         FunctionBody::Extern(call_convention, label) => {
+                            if limit.is_some_and(|limit| func.stack.output > limit.max) {
+                                too_many_regs!(func.stack.output);
+                            }
                             let params = reg_alloc.get(func.stack.input).to_vec();
                             reg_alloc.pop(params.len());
                             write_call(
@@ -521,7 +1140,9 @@ pub fn emit_instructions<'a>(
                                 CallDest::ExactLabel(label),
                                 func.stack,
                                 params,
+                                0,
                                 &mut reg_alloc,
+                                live,
                                 *call_convention,
                                 max_regs,
                             )?;
@@ -532,6 +1153,9 @@ pub fn emit_instructions<'a>(
                 }
             }
             Instruction::IndirectCall(call_convention, stack) => {
+                if limit.is_some_and(|limit| stack.output > limit.max) {
+                    too_many_regs!(stack.output);
+                }
                 let params = reg_alloc.get(stack.input).to_vec();
                 reg_alloc.pop(params.len());
                 let func = reg_alloc.top();
@@ -542,13 +1166,385 @@ pub fn emit_instructions<'a>(
                     CallDest::Slot(func),
                     stack,
                     params,
+                    0,
                     &mut reg_alloc,
+                    live,
                     call_convention,
                     max_regs,
                 )?;
             }
         }
-    })
+    }
+    Ok(errors)
+}
+
+/// Splices the body of every `inline func` into each `call` to it, so that calling it costs nothing.
+///
+/// The callee's args and locals become new locals of the caller, its labels are renamed so they can't collide with
+/// the caller's, and `ret` jumps to right after the call.
+pub fn inline_calls<'a>(functions: &mut BTreeMap<&'a str, Function<'a>>) -> Vec<SourceError<'a>> {
+    let mut errors = Vec::new();
+    let mut inlined = HashMap::new();
+    for &name in functions.keys() {
+        inline_into(name, functions, &mut inlined, &mut Vec::new(), &mut errors);
+    }
+    for (name, (body, new_locals)) in inlined {
+        if let Some(Function {
+            body:
+                FunctionBody::Ursl {
+                    locals,
+                    instructions,
+                    ..
+                },
+            ..
+        }) = functions.get_mut(name)
+        {
+            *locals = new_locals;
+            *instructions = body;
+        }
+    }
+    errors
+}
+
+/// Works out the body and locals of a func after inlining into it. `active` is the chain of funcs that are being
+/// inlined right now, to catch recursion.
+fn inline_into<'a>(
+    name: &'a str,
+    functions: &BTreeMap<&'a str, Function<'a>>,
+    inlined: &mut HashMap<&'a str, (Vec<InstructionEntry<'a>>, usize)>,
+    active: &mut Vec<&'a str>,
+    errors: &mut Vec<SourceError<'a>>,
+) {
+    if inlined.contains_key(name) {
+        return;
+    }
+    let Some(
+        func @ Function {
+            body:
+                FunctionBody::Ursl {
+                    locals,
+                    instructions,
+                    ..
+                },
+            ..
+        },
+    ) = functions.get(name)
+    else {
+        return;
+    };
+    active.push(name);
+    let mut locals = *locals;
+    let mut next_label = instructions
+        .iter()
+        .filter_map(|entry| match entry.instruction {
+            Instruction::Label(LocalLabel::Structured(id, _) | LocalLabel::Inlined(id, _)) => {
+                Some(id + 1)
+            }
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let mut body = Vec::with_capacity(instructions.len());
+    for entry in instructions {
+        let callee = match entry.instruction {
+            Instruction::Call(callee) => functions.get(callee).filter(|callee| {
+                matches!(callee.body, FunctionBody::Ursl { inline: true, .. })
+            }),
+            _ => None,
+        };
+        // a call that underflows the stack is already an error, and there's nothing below it to inline on top of
+        let callee = callee.filter(|callee| entry.enter_height >= callee.stack.input);
+        let Some(callee) = callee else {
+            body.push(entry.clone());
+            continue;
+        };
+        if active.contains(&callee.name) {
+            err!(errors; entry.unit; entry.node, "inline func {} can't be inlined into itself", callee.name);
+            body.push(entry.clone());
+            continue;
+        }
+        inline_into(callee.name, functions, inlined, active, errors);
+        let (callee_body, callee_locals) = &inlined[callee.name];
+
+        // the callee works on top of whatever is below its args
+        let below = entry.enter_height - callee.stack.input;
+        let after = below + callee.stack.output;
+        let base = func.stack.input + locals;
+        locals += callee.stack.input + callee_locals;
+        let at_call = |instruction, enter_height, exit_height: usize| InstructionEntry {
+            excess_height: cmp::min(enter_height, exit_height),
+            enter_height,
+            exit_height: Some(exit_height),
+            instruction,
+            unit: entry.unit,
+            node: entry.node,
+        };
+        // the last arg is on top
+        for arg in (0..callee.stack.input).rev() {
+            body.push(at_call(Instruction::Set(base + arg), below + arg + 1, below + arg));
+        }
+        // locals start out zeroed every time, same as a real call
+        for local in 0..*callee_locals {
+            let idx = base + callee.stack.input + local;
+            body.push(at_call(
+                Instruction::Const(Literal::Num(BigUint::default())),
+                below,
+                below + 1,
+            ));
+            body.push(at_call(Instruction::Set(idx), below + 1, below));
+        }
+
+        let end = LocalLabel::Structured(next_label, "inline");
+        next_label += 1;
+        let mut labels = HashMap::new();
+        let mut rename = |label| {
+            *labels.entry(label).or_insert_with(|| {
+                next_label += 1;
+                match label {
+                    LocalLabel::Named(name) | LocalLabel::Inlined(_, name) => {
+                        LocalLabel::Inlined(next_label - 1, name)
+                    }
+                    LocalLabel::Structured(_, kind) => LocalLabel::Structured(next_label - 1, kind),
+                }
+            })
+        };
+        let mut jumps_to_end = false;
+        for (i, callee_entry) in callee_body.iter().enumerate() {
+            let mut new = callee_entry.clone();
+            new.excess_height += below;
+            new.enter_height += below;
+            new.exit_height = new.exit_height.map(|height| height + below);
+            new.instruction = match callee_entry.instruction {
+                Instruction::Get(idx) => Instruction::Get(base + idx),
+                Instruction::Set(idx) => Instruction::Set(base + idx),
+                Instruction::Ref(idx) => Instruction::Ref(base + idx),
+                Instruction::Height(height) => Instruction::Height(below + height),
+                Instruction::Label(label) => Instruction::Label(rename(label)),
+                Instruction::Jump(label) => Instruction::Jump(rename(label)),
+                Instruction::Branch(prefix, label) => Instruction::Branch(prefix, rename(label)),
+                // the last one just falls through to the code after the call
+                Instruction::Ret if i == callee_body.len() - 1 => continue,
+                Instruction::Ret => {
+                    jumps_to_end = true;
+                    new.excess_height = new.enter_height;
+                    Instruction::Jump(end)
+                }
+                // the caller's frame is still needed, so it's a normal call and then the same as ret
+                Instruction::TailCall(_) | Instruction::TailIndirectCall(_) => {
+                    let (instruction, output) = match callee_entry.instruction {
+                        // an unknown func is already an error, so it doesn't matter what it returns
+                        Instruction::TailCall(name) => (
+                            Instruction::Call(name),
+                            functions.get(name).map_or(0, |callee| callee.stack.output),
+                        ),
+                        Instruction::TailIndirectCall(stack) => (
+                            Instruction::IndirectCall(CallingConvention::URSL, stack),
+                            stack.output,
+                        ),
+                        _ => unreachable!(),
+                    };
+                    new.exit_height = Some(new.excess_height + output);
+                    new.instruction = instruction;
+                    body.push(new);
+                    jumps_to_end = true;
+                    let mut jump = at_call(Instruction::Jump(end), after, after);
+                    jump.exit_height = None;
+                    body.push(jump);
+                    continue;
+                }
+                ref instruction => instruction.clone(),
+            };
+            body.push(new);
+        }
+        if !matches!(
+            callee_body.last().map(|entry| &entry.instruction),
+            Some(Instruction::Ret)
+        ) {
+            // nothing falls through to the code after the call, so the registers need to be reset like after any jump
+            let mut height = at_call(Instruction::Height(after), after, after);
+            height.excess_height = 0;
+            body.push(height);
+        }
+        if jumps_to_end {
+            body.push(at_call(Instruction::Label(end), after, after));
+        }
+    }
+    active.pop();
+    inlined.insert(name, (body, locals));
+}
+
+/// Rewrites `add load`, `add (value) store` and the equivalent `copy` patterns into [`Instruction::Fused`], so they can be emitted as `LLOD` and `LSTR`.
+///
+/// Only the prelude's instructions are fused, since anything else may do whatever it wants.
+/// The value in between `add` and `store` can be anything that doesn't pop, since it doesn't care whether the `add` happened yet.
+fn fuse_addressing<'a>(
+    functions: &BTreeMap<&'a str, Function<'a>>,
+    instructions: &[InstructionEntry<'a>],
+) -> Vec<InstructionEntry<'a>> {
+    let is_prelude = |entry: Option<&InstructionEntry>, name: &str| match entry {
+        Some(InstructionEntry {
+            instruction: Instruction::Call(func),
+            ..
+        }) => {
+            *func == name
+                && functions
+                    .get(func)
+                    .is_some_and(|func| func.unit.path() == PRELUDE_PATH)
+        }
+        _ => false,
+    };
+    let mut fused = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        let add = &instructions[i];
+        let next = instructions.get(i + 1);
+        let after = instructions.get(i + 2);
+        let fuse =
+            |excess_height, enter_height, last: &InstructionEntry<'a>, fusion| InstructionEntry {
+                excess_height,
+                enter_height,
+                exit_height: last.exit_height,
+                instruction: Instruction::Fused(fusion),
+                unit: add.unit,
+                node: add.node,
+            };
+        if !is_prelude(Some(add), "add") {
+            fused.push(add.clone());
+            i += 1;
+        } else if is_prelude(next, "load") {
+            fused.push(fuse(
+                add.excess_height,
+                add.enter_height,
+                &instructions[i + 1],
+                Fusion::Load,
+            ));
+            i += 2;
+        } else if is_prelude(next, "copy") {
+            // the destination of the copy is below the add
+            fused.push(fuse(
+                add.excess_height - 1,
+                add.enter_height,
+                &instructions[i + 1],
+                Fusion::CopyFrom,
+            ));
+            i += 2;
+        } else if matches!(
+            next.map(|entry| &entry.instruction),
+            Some(Instruction::Const(_) | Instruction::Get(_) | Instruction::Ref(_))
+        ) && (is_prelude(after, "store") || is_prelude(after, "copy"))
+        {
+            let mut value = instructions[i + 1].clone();
+            value.excess_height += 1;
+            value.enter_height += 1;
+            value.exit_height = value.exit_height.map(|h| h + 1);
+            let last = &instructions[i + 2];
+            let fusion = if is_prelude(after, "store") {
+                Fusion::Store
+            } else {
+                Fusion::CopyTo
+            };
+            fused.push(value);
+            fused.push(fuse(
+                last.excess_height,
+                last.enter_height + 1,
+                last,
+                fusion,
+            ));
+            i += 3;
+        } else {
+            fused.push(add.clone());
+            i += 1;
+        }
+    }
+    fused
+}
+
+/// For every instruction, which stack slots (indexed from the bottom of the stack) may still be read after it.
+///
+/// Values that are never read again don't need to be saved across calls.
+fn live_slots_after(
+    functions: &BTreeMap<&str, Function>,
+    instructions: &[InstructionEntry],
+) -> Vec<Vec<bool>> {
+    let labels = instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| match entry.instruction {
+            Instruction::Label(label) => Some((label, i)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let mut live_in = vec![Vec::<bool>::new(); instructions.len()];
+    let mut live_out = vec![Vec::<bool>::new(); instructions.len()];
+    let mut changed = true;
+    // loops mean one pass isn't enough, so just go until nothing changes anymore
+    while changed {
+        changed = false;
+        for (i, entry) in instructions.iter().enumerate().rev() {
+            let mut out = Vec::<bool>::new();
+            let mut successors = Vec::new();
+            match entry.instruction {
+                Instruction::Jump(label) => successors.extend(labels.get(&label)),
+                Instruction::Branch(_, label) => {
+                    successors.extend(labels.get(&label));
+                    successors.push(i + 1);
+                }
+                Instruction::Ret
+                | Instruction::Halt
+                | Instruction::TailCall(_)
+                | Instruction::TailIndirectCall(_) => (),
+                _ => successors.push(i + 1),
+            }
+            for successor in successors {
+                if let Some(slots) = live_in.get(successor) {
+                    if out.len() < slots.len() {
+                        out.resize(slots.len(), false);
+                    }
+                    for (out, &slot) in out.iter_mut().zip(slots) {
+                        *out |= slot;
+                    }
+                }
+            }
+
+            let perm = match entry.instruction {
+                Instruction::Perm(ref perm) => Some(perm),
+                Instruction::Call(name) => match functions.get(name) {
+                    Some(Function {
+                        body: FunctionBody::Permutation(perm),
+                        ..
+                    }) => Some(perm),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let excess = entry.excess_height;
+            let live_after = |slot: usize| out.get(slot).copied().unwrap_or(false);
+            let slots = match entry.instruction {
+                Instruction::Height(_) => out.clone(),
+                _ => (0..entry.enter_height)
+                    .map(|slot| {
+                        if slot < excess {
+                            live_after(slot)
+                        } else if let Some(perm) = perm {
+                            // a permutation only reads the inputs that end up somewhere live
+                            perm.output
+                                .iter()
+                                .enumerate()
+                                .any(|(o, &input)| input == slot - excess && live_after(excess + o))
+                        } else {
+                            true
+                        }
+                    })
+                    .collect(),
+            };
+            if slots != live_in[i] {
+                live_in[i] = slots;
+                changed = true;
+            }
+            live_out[i] = out;
+        }
+    }
+    live_out
 }
 
 enum CallDest<'a> {
@@ -565,81 +1561,490 @@ impl Display for CallDest<'_> {
     }
 }
 
+/// Moves every source into its register at once, so that none of them are overwritten before they're read.
+fn parallel_move(
+    f: &mut dyn Write,
+    moves: &[(AllocationSlot, usize)],
+    temp: usize,
+    max_regs: &mut usize,
+) -> io::Result<()> {
+    let mut regs = moves
+        .iter()
+        .filter_map(|(src, dest)| match *src {
+            AllocationSlot::Register(src) if src != *dest => Some((src, *dest)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    while !regs.is_empty() {
+        match regs
+            .iter()
+            .position(|&(_, dest)| !regs.iter().any(|&(src, _)| src == dest))
+        {
+            Some(i) => {
+                let (src, dest) = regs.swap_remove(i);
+                *max_regs = cmp::max(*max_regs, dest);
+                writeln!(f, "MOV ${dest} ${src}")?;
+            }
+            None => {
+                // everything left is a cycle, so one of them has to go through another register
+                let (_, dest) = regs[0];
+                *max_regs = cmp::max(*max_regs, temp);
+                writeln!(f, "MOV ${temp} ${dest}")?;
+                for (src, _) in regs.iter_mut() {
+                    if *src == dest {
+                        *src = temp;
+                    }
+                }
+            }
+        }
+    }
+    // literals don't read any register, so they can be last
+    for (src, dest) in moves {
+        if let AllocationSlot::Literal(lit) = src {
+            *max_regs = cmp::max(*max_regs, *dest);
+            writeln!(f, "IMM ${dest} {lit}")?;
+        }
+    }
+    Ok(())
+}
+
+/// `register_args` is how many of the first params the callee takes in registers, which only URSL funcs do.
+#[allow(clippy::too_many_arguments)]
 fn write_call(
     f: &mut impl Write,
     args: &Args,
     func: CallDest,
     stack: StackBehaviour,
     params: Vec<AllocationSlot>,
+    register_args: usize,
     reg_alloc: &mut RegisterAllocation,
+    live: &[bool],
     call_convention: CallingConvention,
     max_regs: &mut usize,
 ) -> io::Result<()> {
-    match call_convention {
-        // These are equivalent. I think. But I don't think i wanna guarantee that forever by defining that URSL uses the URCL++ calling convention yet.
-        CallingConvention::URCLpp | CallingConvention::URSL => {
-            let used_regs = reg_alloc.all_used_regs();
-            for i in used_regs.iter().copied() {
-                writeln!(f, "PSH ${i}")?;
-            }
-            if params.len() != 0 {
-                if args.verbose {
-                    writeln!(f, "// args")?
-                }
-                for p in params.iter().rev() {
-                    writeln!(f, "PSH {p}")?
-                }
-            }
-            writeln!(f, "CAL {func}")?;
-            if params.len() != 0 {
-                writeln!(f, "ADD SP SP {}", params.len())?;
+    let (reg_params, stack_params) = params.split_at(register_args);
+    let reg_moves = reg_params
+        .iter()
+        .cloned()
+        .zip(1..)
+        .collect::<Vec<_>>();
+    // above everything that's used before the call
+    let temp = reg_alloc
+        .all_used_regs()
+        .into_iter()
+        .chain(reg_params.iter().filter_map(|p| match p {
+            AllocationSlot::Register(reg) => Some(*reg),
+            _ => None,
+        }))
+        .chain([reg_alloc.reserved(), register_args])
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut write_args = |f: &mut dyn Write| -> io::Result<()> {
+        if !stack_params.is_empty() {
+            if args.verbose {
+                writeln!(f, "// args")?
             }
-            for reg in used_regs.into_iter().rev() {
-                *max_regs = cmp::max(*max_regs, reg + stack.output);
-                writeln!(f, "POP ${}", reg + stack.output)?
-            }
-            reg_alloc.offset(stack.output);
-            for reg in 1..=stack.output {
-                *max_regs = cmp::max(*max_regs, reg);
-                reg_alloc.push(AllocationSlot::Register(reg))
-            }
-        }
-        CallingConvention::Hexagn => {
-            let used_regs = reg_alloc.all_used_regs();
-            for i in used_regs.iter().copied() {
-                writeln!(f, "PSH ${i}")?;
-            }
-            if params.len() != 0 {
-                if args.verbose {
-                    writeln!(f, "// args")?
-                }
-                for p in params.iter().rev() {
+            if call_convention.reversed_args {
+                for p in stack_params.iter().rev() {
                     writeln!(f, "PSH {p}")?
                 }
-            }
-            writeln!(f, "CAL {func}")?;
-            if params.len() != 0 {
-                writeln!(f, "ADD SP SP {}", params.len())?;
-            }
-            if stack.output == 1 {
-                for reg in used_regs.into_iter().rev() {
-                    if reg == 1 {
-                        writeln!(f, "POP ${}", reg)?;
-                        *max_regs = cmp::max(*max_regs, reg);
-                    } else {
-                        *max_regs = cmp::max(*max_regs, reg + 1);
-                        writeln!(f, "POP ${}", reg + 1)?
-                    }
-                }
-                reg_alloc.offset_except(1, 1);
-                reg_alloc.push(AllocationSlot::Register(2));
-                *max_regs = cmp::max(*max_regs, 2);
             } else {
-                for i in used_regs.into_iter().rev() {
-                    writeln!(f, "POP ${i}")?
+                for p in stack_params.iter() {
+                    writeln!(f, "PSH {p}")?
                 }
             }
         }
+        parallel_move(f, &reg_moves, temp, max_regs)?;
+        writeln!(f, "CAL {func}")?;
+        if !stack_params.is_empty() && call_convention.caller_cleanup {
+            writeln!(f, "ADD SP SP {}", stack_params.len())?;
+        }
+        Ok(())
+    };
+    let returns = call_convention.returns..call_convention.returns + stack.output;
+    if reg_alloc.limit().is_some() {
+        // Saving registers means popping them all back afterwards, which may not fit.
+        // So instead, spill everything that isn't an arg and leave it there. It'll get reloaded when it's used.
+        while reg_alloc.spill_one(f, reg_alloc.height())? {}
+        write_args(f)?;
+        for reg in returns {
+            *max_regs = cmp::max(*max_regs, reg);
+            reg_alloc.push(AllocationSlot::Register(reg))
+        }
+        return Ok(());
+    }
+    let reserved = reg_alloc.reserved();
+    let mut live_regs = reg_alloc.live_regs(live);
+    // this func's own args in registers are needed for the rest of it, so they're always live
+    live_regs.extend(1..=reserved);
+    // the callee only keeps the regs it doesn't return in, if it keeps any at all
+    let (saved_regs, kept_regs): (Vec<_>, Vec<_>) = live_regs
+        .into_iter()
+        .partition(|reg| call_convention.caller_saves || returns.contains(reg));
+    for i in saved_regs.iter().copied() {
+        writeln!(f, "PSH ${i}")?;
+    }
+    write_args(f)?;
+    if reserved != 0 {
+        // the args have to stay where they are, so the return values move above everything instead
+        let first = cmp::max(temp, returns.end);
+        for (i, reg) in returns.enumerate() {
+            *max_regs = cmp::max(*max_regs, first + i);
+            writeln!(f, "MOV ${} ${reg}", first + i)?;
+        }
+        for reg in saved_regs.into_iter().rev() {
+            writeln!(f, "POP ${reg}")?
+        }
+        for reg in first..first + stack.output {
+            reg_alloc.push(AllocationSlot::Register(reg))
+        }
+        return Ok(());
+    }
+    // everything from the return registers up moves out of their way
+    let moved = |reg: usize| {
+        if reg >= returns.start {
+            reg + stack.output
+        } else {
+            reg
+        }
+    };
+    // highest first, so that it doesn't overwrite any it hasn't moved yet
+    let mut kept_regs = kept_regs;
+    kept_regs.sort_unstable();
+    for reg in kept_regs.into_iter().rev() {
+        if moved(reg) != reg {
+            *max_regs = cmp::max(*max_regs, moved(reg));
+            writeln!(f, "MOV ${} ${reg}", moved(reg))?;
+        }
+    }
+    for reg in saved_regs.into_iter().rev() {
+        *max_regs = cmp::max(*max_regs, moved(reg));
+        writeln!(f, "POP ${}", moved(reg))?
+    }
+    reg_alloc.offset_from(returns.start, stack.output);
+    for reg in returns {
+        *max_regs = cmp::max(*max_regs, reg);
+        reg_alloc.push(AllocationSlot::Register(reg))
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn inline_calls_are_spliced_in() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            inline func $second 2 -> 1 {{ get 1 ret }}
+            inline func $nonzero 1 -> 1 {{
+                get 0 bool branch :yes
+                const 0 ret
+                height 0
+                label :yes
+                const 1 ret
+            }}
+            func $main {{
+                const 1 const 2 call $second out %numb
+                const 5 call $nonzero out %numb
+                const 0 call $nonzero out %numb
+            }}"
+        ))
+        .unwrap();
+        assert!(!func_body(&urcl, "$main").contains("CAL"), "{urcl}");
+        assert!(!urcl.contains(".URSL_func_second\n"), "{urcl}");
+        assert!(!urcl.contains(".URSL_func_nonzero\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "210");
+    }
+
+    #[test]
+    fn inline_funcs_are_still_emitted_for_pointers() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}minstack 16
+            inline func $seven 0 -> 1 {{ const 7 ret }}
+            func $main {{ call $seven out %numb const $seven icall 0 -> 1 out %numb }}"
+        ))
+        .unwrap();
+        assert!(urcl.contains(".URSL_func_seven\n"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "77");
+    }
+
+    #[test]
+    fn inlined_locals_are_reserved_without_zeroing() {
+        let args = Args {
+            garbage_initialized_locals: true,
+            ..Args::default()
+        };
+        let urcl = compile_with(
+            &args,
+            &format!(
+                "{HEADERS}
+                inline func $square 1 -> 1 + 1 {{ get 0 get 0 mult set 1 get 1 ret }}
+                func $main {{ const 3 call $square const 4 call $square add out %numb }}"
+            ),
+        )
+        .unwrap();
+        assert!(func_body(&urcl, "$main").contains("SUB SP SP"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "25");
+    }
+
+    #[test]
+    fn inline_recursion_is_an_error() {
+        assert_error(
+            &format!(
+                "{HEADERS}minstack 16
+                inline func $f 0 -> 0 {{ call $f }}
+                func $main {{ call $f }}"
+            ),
+            "inline func $f can't be inlined into itself",
+        );
+    }
+
+    #[test]
+    fn inline_call_that_underflows_is_an_error() {
+        assert_error(
+            &format!(
+                "{HEADERS}
+                inline func $inl 1 -> 0 {{ }}
+                func $main {{ call $inl }}"
+            ),
+            "Stack underflow",
+        );
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        // with a real call, this would need a frame for every number
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            func $sum 2 -> 1 {{
+                get 0 bool branch :more
+                get 1 ret
+                height 0
+                label :more
+                get 0 dec
+                get 1 get 0 add
+                tailcall $sum
+            }}
+            func $main {{ const 20 const 0 call $sum out %numb }}"
+        ))
+        .unwrap();
+        assert!(!func_body(&urcl, "$sum").contains("CAL"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "210");
+    }
+
+    #[test]
+    fn tail_calls_through_pointers_and_inlining() {
+        let source = format!(
+            "{HEADERS}minstack 16
+            func $double 1 -> 1 {{ get 0 get 0 add ret }}
+            func $via 1 -> 1 {{ const $double get 0 tailicall 1 -> 1 }}
+            inline func $inlined 1 -> 1 {{ get 0 tailcall $double }}
+            func $main {{ const 21 call $via out %numb const 4 call $inlined out %numb }}"
+        );
+        assert_eq!(run_source(&source), "428");
+    }
+
+    #[test]
+    fn tail_calls_must_fit_the_frame() {
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 1 -> 0 {{ }}
+                func $g 1 -> 0 {{ const 1 const 2 tailcall $f }}
+                func $main {{ const 0 call $g }}"
+            ),
+            "Bad stack height (height here is 2, but tailcall $f takes 1)",
+        );
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 1 -> 1 {{ get 0 ret }}
+                func $g 1 -> 0 {{ get 0 tailcall $f }}
+                func $main {{ const 0 call $g }}"
+            ),
+            "tailcall $f returns 1, but function returns 0",
+        );
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 2 -> 0 {{ }}
+                func $g 1 -> 0 {{ get 0 get 0 tailcall $f }}
+                func $main {{ const 0 call $g }}"
+            ),
+            "tailcall $f takes 2 args, but there are only 1 arg slots here to put them in",
+        );
+    }
+
+    #[test]
+    fn if_label_is_its_end() {
+        compile_source(&format!(
+            "{HEADERS}
+            func $main {{
+                const 1 bool if :cond {{ jump :cond }}
+            }}"
+        ))
+        .unwrap();
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $main {{
+                    const 0 bool branch :cond
+                    const 1 bool if :cond {{ }}
+                }}"
+            ),
+            "Branch or jump to :cond from outside of its if",
+        );
+    }
+
+    #[test]
+    fn only_live_values_are_saved_around_calls() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            func $g 0 -> 0 {{ }}
+            func $dead 1 -> 0 {{ get 0 call $g pop }}
+            func $live 1 -> 0 {{ get 0 call $g out %numb }}
+            func $main {{ const 4 call $dead const 5 call $live }}"
+        ))
+        .unwrap();
+        assert!(!func_body(&urcl, "$dead").contains("PSH"), "{urcl}");
+        assert!(func_body(&urcl, "$live").contains("PSH"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "5");
+    }
+
+    #[test]
+    fn values_read_after_a_loop_are_live_inside_it() {
+        let source = format!(
+            "{HEADERS}
+            func $g 0 -> 0 {{ }}
+            func $loop 1 -> 0 + 1 {{
+                get 0
+                const 3 set 1
+                label :loop
+                call $g
+                get 1 dec set 1
+                get 1 bool branch :loop
+                out %numb
+            }}
+            func $main {{ const 8 call $loop }}"
+        );
+        assert_eq!(run_source(&source), "8");
+    }
+
+    #[test]
+    fn named_args_and_locals() {
+        compile_source(&format!(
+            "{HEADERS}
+            func $add (a b) -> 1 + (sum) {{ get a get b add set sum get sum ret }}
+            func $main {{ const 1 const 2 call $add out %numb }}"
+        ))
+        .unwrap();
+        assert_error(
+            &format!("{HEADERS} func $main 0 -> 0 + (x) {{ get y set x }}"),
+            "Unknown arg or local y",
+        );
+        assert_error(
+            &format!("{HEADERS} func $main {{ const 1 block (a) -> 0 {{ pop }} }}"),
+            "Args can only be named in the head of a func",
+        );
+    }
+
+    #[test]
+    fn add_then_load_is_llod() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            .arr [ 10 20 30 40 ]
+            func $nth 1 -> 1 {{ get 0 const 3 add load ret }}
+            func $main {{ const .arr call $nth out %numb const .arr const 1 add load out %numb }}"
+        ))
+        .unwrap();
+        assert!(func_body(&urcl, "$nth").contains("LLOD"), "{urcl}");
+        assert!(func_body(&urcl, "$main").contains("LLOD"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "4020");
+    }
+
+    #[test]
+    fn add_then_store_is_lstr() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            .arr [ 10 20 30 40 ]
+            func $poke 1 -> 0 {{ get 0 const 1 add const 99 store ret }}
+            func $main {{ const .arr call $poke const .arr const 1 add load out %numb }}"
+        ))
+        .unwrap();
+        assert!(func_body(&urcl, "$poke").contains("LSTR"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "99");
+    }
+
+    #[test]
+    fn add_around_copy_is_fused() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            .arr [ 10 20 30 40 ]
+            func $rotate 0 -> 0 + 1 {{
+                ref 0 const .arr const 3 add copy
+                const .arr const 0 add ref 0 copy
+                ret
+            }}
+            func $main {{ call $rotate const .arr load out %numb }}"
+        ))
+        .unwrap();
+        let rotate = func_body(&urcl, "$rotate");
+        assert!(rotate.contains("LLOD"), "{urcl}");
+        assert!(rotate.contains("LSTR"), "{urcl}");
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "40");
+    }
+
+    #[test]
+    fn out_of_range_numbers_and_locals_are_errors() {
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 99999999999999999999999 -> 0 {{ ret }}
+                func $main {{ }}"
+            ),
+            "Invalid number 99999999999999999999999",
+        );
+        assert_error(
+            &format!("{HEADERS} func $main {{ get 99999999999999999999999 pop }}"),
+            "Local 99999999999999999999999 is out of bounds",
+        );
+        assert_error(
+            &format!(
+                "{HEADERS}
+                func $f 1 -> 0 {{ get 5 pop ret }}
+                func $main {{ const 1 call $f }}"
+            ),
+            "Local 5 is out of bounds, there are only 1 args and locals here",
+        );
+    }
+
+    #[test]
+    fn exports_get_their_label_and_convention() {
+        let urcl = compile_source(&format!(
+            "{HEADERS}
+            export \"URCL++\" func $twice 1 -> 1 = .twice {{ get 0 get 0 add ret }}
+            export \"Hexagn\" func $thrice 1 -> 1 = .thrice_int_int {{ get 0 get 0 add get 0 add ret }}
+            export \"URCL++\" func $uncalled 0 -> 0 = .uncalled {{ ret }}
+            func $main {{ const 4 call $twice out %numb const 4 call $thrice out %numb }}"
+        ))
+        .unwrap();
+        assert!(urcl.contains(".URSL_func_twice\n.twice\n"), "{urcl}");
+        assert!(
+            urcl.contains(".URSL_func_thrice\n.thrice_int_int\n"),
+            "{urcl}"
+        );
+        // nothing in URSL calls it, but whatever links with this might
+        assert!(urcl.contains(".URSL_func_uncalled\n.uncalled\n"), "{urcl}");
+        // URCL++ returns in $1 like URSL, but Hexagn moves it to $2
+        assert!(!func_body(&urcl, "$twice").contains("MOV $2 $1"), "{urcl}");
+        assert!(
+            func_body(&urcl, "$thrice").contains("MOV $2 $1\nRET\n"),
+            "{urcl}"
+        );
+        assert_eq!(emulate_urcl(&urcl).unwrap(), "812");
+    }
+}